@@ -1,6 +1,21 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use crate::error::CommitGenError;
 
-#[derive(Debug, Deserialize)]
+// toml widens f32 to f64 on output, which turns 0.9 into 0.8999999761581421
+fn serialize_f32<S: Serializer>(value: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(value.to_string().parse().unwrap_or(*value as f64))
+}
+
+fn serialize_option_f32<S: Serializer>(value: &Option<f32>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serialize_f32(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub model: ModelConfig,
     pub commit: CommitConfig,
@@ -8,59 +23,351 @@ pub struct Config {
     pub selection: FileSelectionConfig,
     pub formatting: FormattingConfig,
     pub prompts: PromptsConfig,
+    #[serde(default)]
+    pub lint: LintConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub rewrites: Vec<Rewrite>,
 }
 
-#[derive(Debug, Deserialize)]
+// a regex find/replace on the generated message; the replacement can use $1 or ${name}
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Rewrite {
+    pub pattern: String,
+    pub replacement: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct ModelConfig {
     pub name: String,
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(serialize_with = "serialize_f32")]
     pub top_p: f32,
     pub max_tokens: u32,
+    #[serde(serialize_with = "serialize_f32")]
     pub file_selection_temperature: f32,
+    #[serde(serialize_with = "serialize_f32")]
     pub commit_temperature: f32,
+    #[serde(serialize_with = "serialize_option_f32")]
+    pub description_temperature: Option<f32>,
+    #[serde(default)]
+    pub strict_xml: bool,
+    #[serde(default)]
+    pub max_format_retries: u32,
+    #[serde(default = "default_retry_temperature_floor", serialize_with = "serialize_f32")]
+    pub retry_temperature_floor: f32,
+    #[serde(default)]
+    pub fallback_on_error: bool,
+    #[serde(default)]
+    pub fallback_models: Vec<String>,
+    #[serde(default)]
+    pub response_format: ResponseFormat,
+    pub max_model_calls: Option<u32>,
+    pub keep_alive: Option<String>,
+    pub num_ctx: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+    #[default]
+    Xml,
+    Json,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct CommitConfig {
     pub conventional: bool,
     pub emoji: bool,
+    #[serde(default)]
+    pub emoji_position: EmojiPosition,
     pub max_message_length: u32,
+    pub max_subject_length: Option<usize>,
+    pub max_body_length: Option<usize>,
+    pub post_commit_command: Option<String>,
+    #[serde(default)]
+    pub confirm_files: bool,
+    #[serde(default)]
+    pub gerrit_change_id: bool,
+    #[serde(default)]
+    pub subject_case: SubjectCase,
+    #[serde(default)]
+    pub strip_trailing_period: bool,
+    #[serde(default)]
+    pub include_diff_in_body: bool,
+    #[serde(default = "default_true")]
+    pub default_confirm: bool,
+    #[serde(default)]
+    pub always_edit: bool,
+    #[serde(default)]
+    pub edit_verbose: bool,
+    pub bullet_marker: Option<char>,
+    #[serde(default = "default_issue_footer_format")]
+    pub issue_footer_format: String,
+    #[serde(default = "default_pr_footer_format")]
+    pub pr_footer_format: String,
+    pub subject_ticket_prefix: Option<String>,
+    #[serde(default = "default_ticket_pattern")]
+    pub ticket_pattern: String,
+    #[serde(default = "default_dedupe_commits")]
+    pub dedupe_commits: usize,
+    #[serde(default = "default_dedupe_threshold")]
+    pub dedupe_threshold: f64,
+    #[serde(default)]
+    pub on_duplicate: DuplicateAction,
 }
 
-#[derive(Debug, Deserialize)]
+// what happens when the subject nearly repeats a recent commit's
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateAction {
+    #[default]
+    Warn,
+    Regenerate,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EmojiPosition {
+    #[default]
+    Prefix,
+    AfterType,
+    None,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct GitConfig {
     pub include_staged: bool,
     pub include_unstaged: bool,
     pub exclude_patterns: Vec<String>,
+    #[serde(default)]
+    pub stage_exclude: Vec<String>,
+    #[serde(default)]
+    pub protected_branches: Vec<String>,
+    #[serde(default)]
+    pub warn_markers: Vec<String>,
+    #[serde(default)]
+    pub whitespace: WhitespaceMode,
+}
+
+// what the model sees of an added or untracked file
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NewFileContent {
+    #[default]
+    Full,
+    Names,
+}
+
+// how much whitespace difference the diffs ignore, like git diff's -b and -w
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WhitespaceMode {
+    #[default]
+    Keep,
+    IgnoreChange,
+    IgnoreAll,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct FileSelectionConfig {
     pub min_files: usize,
     pub max_files: usize,
     pub prioritize_src: bool,
     pub exclude_tests: bool,
     pub min_changes: usize,
+    #[serde(default)]
+    pub priority_extensions: Vec<String>,
+    #[serde(default)]
+    pub deprioritize_extensions: Vec<String>,
+    #[serde(default)]
+    pub include_extensions: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct FormattingConfig {
     pub max_diff_lines: usize,
     pub preview_lines: usize,
     pub summary_lines: usize,
     pub indent_size: usize,
     pub show_file_stats: bool,
+    pub total_diff_lines: Option<usize>,
+    pub max_diff_bytes: Option<usize>,
+    pub max_other_files: Option<usize>,
+    #[serde(default)]
+    pub summarize_lockfiles: bool,
+    #[serde(default = "default_lockfile_patterns")]
+    pub lockfile_patterns: Vec<String>,
+    #[serde(default)]
+    pub adaptive_context: bool,
+    #[serde(default = "default_small_change_lines")]
+    pub small_change_lines: usize,
+    #[serde(default = "default_small_change_context")]
+    pub small_change_context: u32,
+    #[serde(default = "default_large_change_lines")]
+    pub large_change_lines: usize,
+    #[serde(default)]
+    pub new_file_content: NewFileContent,
+    #[serde(default)]
+    pub label_staged: bool,
+    #[serde(default)]
+    pub details_staged_only: bool,
+    pub stat_only_threshold: Option<usize>,
+    #[serde(default)]
+    pub strip_comments: bool,
+    #[serde(default = "default_comment_prefixes")]
+    pub comment_prefixes: BTreeMap<String, Vec<String>>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_issue_footer_format() -> String {
+    "Fixes issue #{number}".to_string()
+}
+
+fn default_pr_footer_format() -> String {
+    "Related to PR #{number}".to_string()
+}
+
+fn default_dedupe_commits() -> usize {
+    5
+}
+
+fn default_dedupe_threshold() -> f64 {
+    0.85
+}
+
+fn default_ticket_pattern() -> String {
+    "[A-Z][A-Z0-9]+-[0-9]+".to_string()
+}
+
+fn default_retry_temperature_floor() -> f32 {
+    0.1
 }
 
-#[derive(Debug, Deserialize)]
+fn default_host() -> String {
+    "http://localhost".to_string()
+}
+
+fn default_port() -> u16 {
+    11434
+}
+
+fn default_lockfile_patterns() -> Vec<String> {
+    ["Cargo.lock", "package-lock.json", "yarn.lock", "pnpm-lock.yaml", "poetry.lock", "Gemfile.lock", "composer.lock", "go.sum"]
+        .iter()
+        .map(|pattern| pattern.to_string())
+        .collect()
+}
+
+fn default_small_change_lines() -> usize {
+    20
+}
+
+fn default_small_change_context() -> u32 {
+    10
+}
+
+fn default_large_change_lines() -> usize {
+    300
+}
+
+fn default_comment_prefixes() -> BTreeMap<String, Vec<String>> {
+    let slashes = ["rs", "c", "h", "cpp", "hpp", "cs", "go", "java", "kt", "swift", "js", "jsx", "ts", "tsx"];
+    let hashes = ["py", "rb", "sh", "bash", "toml", "yaml", "yml"];
+    let dashes = ["sql", "lua", "hs"];
+    [(&slashes[..], "//"), (&hashes[..], "#"), (&dashes[..], "--")]
+        .iter()
+        .flat_map(|(extensions, prefix)| extensions.iter().map(move |extension| (extension.to_string(), vec![prefix.to_string()])))
+        .collect()
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct PromptsConfig {
     pub file_selection_system: String,
     pub file_selection_context: String,
     pub commit_system: String,
     pub commit_context: String,
+    pub description_context: Option<String>,
+    #[serde(default = "default_pr_system")]
+    pub pr_system: String,
+    #[serde(default = "default_pr_context")]
+    pub pr_context: String,
+    #[serde(default = "default_review_system")]
+    pub review_system: String,
+    #[serde(default = "default_review_context")]
+    pub review_context: String,
+    #[serde(default = "default_subject_context")]
+    pub subject_context: String,
+    #[serde(default = "default_compare_system")]
+    pub compare_system: String,
+    #[serde(default = "default_compare_context")]
+    pub compare_context: String,
+    #[serde(default = "default_release_system")]
+    pub release_system: String,
+    #[serde(default = "default_release_context")]
+    pub release_context: String,
     pub placeholders: PromptPlaceholders,
+    #[serde(default)]
+    pub tags: TagsConfig,
+    #[serde(default)]
+    pub sections: SectionsConfig,
+}
+
+// the framing around the diffs in {changes_text}; file headers can use {path}, {status}
+// and {lines}, the omitted-files line {count}
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SectionsConfig {
+    pub detailed: String,
+    pub other: String,
+    pub file: String,
+    pub file_with_stats: String,
+    pub omitted: String,
+}
+
+impl Default for SectionsConfig {
+    fn default() -> Self {
+        SectionsConfig {
+            detailed: "Detailed changes in selected files:".to_string(),
+            other: "Other changes (summarized):".to_string(),
+            file: "In {path} ({status}):".to_string(),
+            file_with_stats: "In {path} ({status}) - {lines} lines changed:".to_string(),
+            omitted: "...and {count} more files".to_string(),
+        }
+    }
+}
+
+// element names the responses are parsed with; the prompts must ask for the same ones
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TagsConfig {
+    pub files: String,
+    pub file: String,
+    pub commit: String,
+    pub message: String,
+    pub description: String,
+}
+
+impl Default for TagsConfig {
+    fn default() -> Self {
+        TagsConfig {
+            files: "files".to_string(),
+            file: "file".to_string(),
+            commit: "commit".to_string(),
+            message: "message".to_string(),
+            description: "description".to_string(),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct PromptPlaceholders {
     pub changes_summary: String,
     pub changes_text: String,
@@ -68,4 +375,290 @@ pub struct PromptPlaceholders {
     pub max_message_length: String,
     pub min_files: String,
     pub max_files: String,
-}
\ No newline at end of file
+    #[serde(default = "default_changed_symbols")]
+    pub changed_symbols: String,
+    #[serde(default = "default_subject")]
+    pub subject: String,
+    #[serde(default = "default_commit_subjects")]
+    pub commit_subjects: String,
+    #[serde(default = "default_repo_name")]
+    pub repo_name: String,
+    #[serde(default = "default_commit_message")]
+    pub commit_message: String,
+    #[serde(default = "default_body")]
+    pub body: String,
+    #[serde(default = "default_draft")]
+    pub draft: String,
+}
+
+impl PromptsConfig {
+    // catches templates and placeholder names drifting apart, which would otherwise
+    // send the model prompts with unsubstituted {placeholders}
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let p = &self.placeholders;
+        // changed_symbols and subject are extras that templates may leave out
+        let declared = [
+            ("changes_summary", &p.changes_summary, true),
+            ("changes_text", &p.changes_text, true),
+            ("indent_size", &p.indent_size, true),
+            ("max_message_length", &p.max_message_length, true),
+            ("min_files", &p.min_files, true),
+            ("max_files", &p.max_files, true),
+            ("changed_symbols", &p.changed_symbols, false),
+            ("subject", &p.subject, false),
+            ("commit_subjects", &p.commit_subjects, false),
+            ("repo_name", &p.repo_name, false),
+            ("commit_message", &p.commit_message, false),
+            ("body", &p.body, false),
+            ("draft", &p.draft, false),
+        ];
+        let templates = [
+            ("file_selection_system", Some(&self.file_selection_system)),
+            ("file_selection_context", Some(&self.file_selection_context)),
+            ("commit_system", Some(&self.commit_system)),
+            ("commit_context", Some(&self.commit_context)),
+            ("description_context", self.description_context.as_ref()),
+            ("pr_system", Some(&self.pr_system)),
+            ("pr_context", Some(&self.pr_context)),
+            ("review_system", Some(&self.review_system)),
+            ("review_context", Some(&self.review_context)),
+            ("subject_context", Some(&self.subject_context)),
+            ("compare_system", Some(&self.compare_system)),
+            ("compare_context", Some(&self.compare_context)),
+            ("release_system", Some(&self.release_system)),
+            ("release_context", Some(&self.release_context)),
+        ];
+        let templates: Vec<_> = templates.into_iter()
+            .filter_map(|(name, template)| template.map(|template| (name, template)))
+            .collect();
+
+        let mut problems = Vec::new();
+        for (name, value, required) in declared {
+            if required && !templates.iter().any(|(_, template)| template.contains(value.as_str())) {
+                problems.push(format!("placeholders.{} = \"{}\" is not used by any prompt", name, value));
+            }
+        }
+
+        let reference = regex::Regex::new(r"\{[a-z_]+\}").unwrap();
+        for (name, template) in &templates {
+            for found in reference.find_iter(template) {
+                let found = found.as_str();
+                let known = found == "{indent}" || declared.iter().any(|(_, value, _)| value.as_str() == found);
+                if !known {
+                    problems.push(format!("prompts.{} references undeclared placeholder {}", name, found));
+                }
+            }
+        }
+
+        let s = &self.sections;
+        let sections = [
+            ("detailed", &s.detailed, &[][..]),
+            ("other", &s.other, &[][..]),
+            ("file", &s.file, &["{path}", "{status}", "{lines}"][..]),
+            ("file_with_stats", &s.file_with_stats, &["{path}", "{status}", "{lines}"][..]),
+            ("omitted", &s.omitted, &["{count}"][..]),
+        ];
+        for (name, template, allowed) in sections {
+            for found in reference.find_iter(template) {
+                if !allowed.contains(&found.as_str()) {
+                    problems.push(format!("prompts.sections.{} references unknown placeholder {}", name, found.as_str()));
+                }
+            }
+        }
+
+        let expected_tags = [
+            ("file_selection", [&self.file_selection_system, &self.file_selection_context], [&self.tags.files, &self.tags.file]),
+            ("commit", [&self.commit_system, &self.commit_context], [&self.tags.commit, &self.tags.message]),
+        ];
+        for (stage, stage_templates, tags) in expected_tags {
+            for tag in tags {
+                let tag = format!("<{}>", tag);
+                if !stage_templates.iter().any(|template| template.contains(&tag)) {
+                    problems.push(format!("the {} prompts never ask for the {} tag set in [prompts.tags]", stage, tag));
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(CommitGenError::InvalidPrompts(problems).into())
+        }
+    }
+}
+
+fn default_changed_symbols() -> String {
+    "{changed_symbols}".to_string()
+}
+
+fn default_subject() -> String {
+    "{subject}".to_string()
+}
+
+fn default_commit_subjects() -> String {
+    "{commit_subjects}".to_string()
+}
+
+fn default_repo_name() -> String {
+    "{repo_name}".to_string()
+}
+
+fn default_commit_message() -> String {
+    "{commit_message}".to_string()
+}
+
+fn default_body() -> String {
+    "{body}".to_string()
+}
+
+fn default_draft() -> String {
+    "{draft}".to_string()
+}
+
+fn default_pr_system() -> String {
+    "You are a precise XML generator writing pull request descriptions. Output ONLY the exact XML \
+    structure requested. The title must be a single line and the body must be markdown."
+        .to_string()
+}
+
+fn default_pr_context() -> String {
+    r#"Summarize the work on this branch as a pull request.
+
+=== Commits ===
+{commit_subjects}
+
+=== Changes Summary ===
+{changes_summary}
+
+=== Detailed Changes ===
+{changes_text}
+
+Output MUST be valid XML with this EXACT format:
+<pr>
+<title>Short PR title (max {max_message_length} chars)</title>
+<body>
+A short summary paragraph, then a markdown bullet list of the notable changes
+</body>
+</pr>"#
+        .to_string()
+}
+
+fn default_review_system() -> String {
+    "You are an experienced code reviewer giving feedback on commit messages. Be specific and brief, \
+    and answer in plain text."
+        .to_string()
+}
+
+fn default_review_context() -> String {
+    r#"Review how well this commit message describes the commit's changes.
+
+=== Commit Message ===
+{commit_message}
+
+=== Changes Summary ===
+{changes_summary}
+
+=== Detailed Changes ===
+{changes_text}
+
+Say whether the message is accurate and complete. Point out anything it gets wrong,
+important changes it leaves out, and wording that is vague, then suggest a better
+message if one is needed."#
+        .to_string()
+}
+
+fn default_subject_context() -> String {
+    r#"Write a new subject line for a commit whose body is already written.
+
+=== Body ===
+{body}
+
+=== Changes Summary ===
+{changes_summary}
+
+=== Detailed Changes ===
+{changes_text}
+
+The subject must summarize the change in at most {max_message_length} characters,
+in the imperative mood, without a trailing period.
+
+Output ONLY <message>the subject line</message>."#
+        .to_string()
+}
+
+fn default_compare_system() -> String {
+    "You are an experienced developer coaching a colleague on writing commit messages. \
+    Be specific and brief, and answer in plain text."
+        .to_string()
+}
+
+fn default_compare_context() -> String {
+    r#"A developer wrote a draft commit message, and another message was generated for
+the same changes. Compare the two.
+
+=== Draft ===
+{draft}
+
+=== Generated ===
+{commit_message}
+
+=== Changes Summary ===
+{changes_summary}
+
+=== Detailed Changes ===
+{changes_text}
+
+Say what each message gets right, what the draft leaves out or gets wrong about the
+changes, and how its wording could be clearer. Keep it to a few short points."#
+        .to_string()
+}
+
+fn default_release_system() -> String {
+    "You are a technical writer preparing release notes. Write plain markdown for the \
+    project's users, without XML."
+        .to_string()
+}
+
+fn default_release_context() -> String {
+    r#"Write the introduction to the release notes for these changes.
+
+=== Commits ===
+{commit_subjects}
+
+=== Changes Summary ===
+{changes_summary}
+
+=== Detailed Changes ===
+{changes_text}
+
+In one or two short markdown paragraphs, say what this release brings for its users,
+most important changes first. Don't list every commit; they are listed separately.
+Output only the paragraphs."#
+        .to_string()
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct LogConfig {
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct LintConfig {
+    pub subject_max_length: Option<usize>,
+    #[serde(default)]
+    pub subject_case: SubjectCase,
+    #[serde(default)]
+    pub require_type: bool,
+    #[serde(default)]
+    pub require_body: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubjectCase {
+    #[default]
+    #[serde(alias = "preserve")]
+    Any,
+    Lower,
+    Sentence,
+}
@@ -0,0 +1,83 @@
+use ollama_rs::error::OllamaError;
+use thiserror::Error;
+
+// what git.rs and ollama.rs fail with, so callers can match on the kind of failure;
+// everything without a variant of its own is Git or Other. the rest of the binary uses
+// anyhow, where these are found again with `error.downcast_ref::<CommitGenError>()`
+pub type Result<T, E = CommitGenError> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum CommitGenError {
+    #[error("Cannot find a config file")]
+    ConfigNotFound,
+
+    #[error("Invalid prompt configuration:\n  {}", .0.join("\n  "))]
+    InvalidPrompts(Vec<String>),
+
+    #[error("Not in a git repository")]
+    NoRepository(#[source] git2::Error),
+
+    #[error("Request to model '{model}' failed")]
+    ModelRequest {
+        model: String,
+        #[source]
+        source: OllamaError,
+    },
+
+    #[error("Ollama server not reachable at {url}; is `ollama serve` running?")]
+    OllamaUnreachable {
+        url: String,
+        #[source]
+        source: OllamaError,
+    },
+
+    #[error("Model '{0}' is not pulled on the Ollama server; run `ollama pull {0}`")]
+    ModelNotPulled(String),
+
+    #[error("Model '{0}' returned an empty response")]
+    ModelEmptyResponse(String),
+
+    #[error("Reached the limit of {0} model calls for this run (model.max_model_calls)")]
+    CallBudgetExceeded(u32),
+
+    #[error("Invalid date '{date}'. Use {expected}")]
+    InvalidDate {
+        date: String,
+        expected: &'static str,
+    },
+
+    #[error("Nothing to commit (use --allow-empty to commit anyway)")]
+    NothingToCommit,
+
+    #[error("Refusing to commit with an empty message; the model likely returned nothing (use --allow-empty-message to commit anyway)")]
+    EmptyMessage,
+
+    #[error("Refusing to commit directly to protected branch '{0}' (pass --allow-protected)")]
+    ProtectedBranch(String),
+
+    #[error("Gave up after {0}s (--timeout); nothing was committed")]
+    TimedOut(u64),
+
+    #[error("Commit message failed lint with {0} violation(s)")]
+    LintFailed(usize),
+
+    #[error(transparent)]
+    Git(#[from] git2::Error),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl CommitGenError {
+    // the model side failed rather than git or the config; the binary exits with 5 for these
+    pub fn is_model_failure(&self) -> bool {
+        matches!(
+            self,
+            CommitGenError::ModelRequest { .. }
+                | CommitGenError::OllamaUnreachable { .. }
+                | CommitGenError::ModelNotPulled(_)
+                | CommitGenError::ModelEmptyResponse(_)
+                | CommitGenError::CallBudgetExceeded(_)
+        )
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use chrono::Local;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+pub enum JobStatus {
+    Pending,
+    Done(String),
+    Failed(String),
+    // finished without a message, e.g. there was nothing to commit
+    Ended(String),
+}
+
+fn jobs_dir() -> Result<PathBuf> {
+    let cache = dirs::cache_dir().ok_or_else(|| anyhow::anyhow!("Could not determine cache directory"))?;
+    let dir = cache.join("commit-gen/jobs");
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    Ok(dir)
+}
+
+// re-runs the current command line detached, with --background swapped for --job <id>
+pub fn spawn() -> Result<String> {
+    let id = format!("{}-{}", Local::now().format("%Y%m%d%H%M%S"), std::process::id());
+    let dir = jobs_dir()?;
+    std::fs::write(dir.join(format!("{}.pending", id)), "")?;
+
+    let args: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| arg != "--background")
+        .collect();
+
+    Command::new(std::env::current_exe()?)
+        .args(&args)
+        .args(["--yes", "--job", &id])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("Failed to start background generation")?;
+
+    Ok(id)
+}
+
+// the --job id of this process, read straight from the command line for when it
+// doesn't parse
+pub fn id_from_args() -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != "--job");
+    args.next()?;
+    args.next()
+}
+
+pub fn write_result(id: &str, message: &str) -> Result<()> {
+    let dir = jobs_dir()?;
+    std::fs::write(dir.join(format!("{}.txt", id)), message)?;
+    let _ = std::fs::remove_file(dir.join(format!("{}.pending", id)));
+    Ok(())
+}
+
+pub fn write_error(id: &str, error: &anyhow::Error) -> Result<()> {
+    let dir = jobs_dir()?;
+    std::fs::write(dir.join(format!("{}.err", id)), format!("{:#}", error))?;
+    let _ = std::fs::remove_file(dir.join(format!("{}.pending", id)));
+    Ok(())
+}
+
+// how a job that produced no message or error ended, so it doesn't stay pending
+pub fn write_status(id: &str, status: &str) -> Result<()> {
+    let dir = jobs_dir()?;
+    std::fs::write(dir.join(format!("{}.status", id)), status)?;
+    let _ = std::fs::remove_file(dir.join(format!("{}.pending", id)));
+    Ok(())
+}
+
+pub fn read_result(id: &str) -> Result<JobStatus> {
+    let dir = jobs_dir()?;
+
+    if let Ok(message) = std::fs::read_to_string(dir.join(format!("{}.txt", id))) {
+        return Ok(JobStatus::Done(message));
+    }
+    if let Ok(error) = std::fs::read_to_string(dir.join(format!("{}.err", id))) {
+        return Ok(JobStatus::Failed(error));
+    }
+    if let Ok(status) = std::fs::read_to_string(dir.join(format!("{}.status", id))) {
+        return Ok(JobStatus::Ended(status));
+    }
+    if dir.join(format!("{}.pending", id)).exists() {
+        return Ok(JobStatus::Pending);
+    }
+    Err(anyhow::anyhow!("No background job with id {}", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_change_job_is_not_left_pending() {
+        let id = format!("test-{}-no-changes", std::process::id());
+        let dir = jobs_dir().unwrap();
+        std::fs::write(dir.join(format!("{}.pending", id)), "").unwrap();
+
+        write_status(&id, "no changes").unwrap();
+        let status = read_result(&id).unwrap();
+
+        let _ = std::fs::remove_file(dir.join(format!("{}.status", id)));
+        assert!(matches!(status, JobStatus::Ended(status) if status == "no changes"));
+        assert!(!dir.join(format!("{}.pending", id)).exists());
+    }
+}
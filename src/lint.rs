@@ -0,0 +1,75 @@
+use regex::Regex;
+
+use crate::config::{LintConfig, SubjectCase};
+
+// acronyms and identifiers like "API" or "README" keep their case in a lowercase subject
+pub fn starts_with_acronym(text: &str) -> bool {
+    text.chars().nth(1).is_some_and(char::is_uppercase)
+}
+
+pub fn lint_message(config: &LintConfig, message: &str) -> Vec<String> {
+    let mut violations = Vec::new();
+    let mut lines = message.lines();
+    let subject = lines.next().unwrap_or("").trim();
+
+    if let Some(max) = config.subject_max_length {
+        let length = subject.chars().count();
+        if length > max {
+            violations.push(format!("subject is {} characters long (max {})", length, max));
+        }
+    }
+
+    // leading emoji and whitespace aren't part of the type
+    let without_emoji = subject.trim_start_matches(|c: char| !c.is_ascii_alphanumeric());
+    let type_re = Regex::new(r"^[a-z]+(\([^)]*\))?!?: ").unwrap();
+    let typed = type_re.find(without_emoji);
+
+    if config.require_type && typed.is_none() {
+        violations.push("subject has no conventional commit type (e.g. \"feat: ...\")".to_string());
+    }
+
+    let description = match typed {
+        Some(m) => &without_emoji[m.end()..],
+        None => without_emoji,
+    };
+    if let Some(first) = description.chars().next() {
+        match config.subject_case {
+            SubjectCase::Lower if first.is_uppercase() && !starts_with_acronym(description) => {
+                violations.push("subject must start with a lowercase letter".to_string());
+            }
+            SubjectCase::Sentence if first.is_lowercase() => {
+                violations.push("subject must start with an uppercase letter".to_string());
+            }
+            _ => {}
+        }
+    }
+
+    if config.require_body && lines.all(|line| line.trim().is_empty()) {
+        violations.push("message has no body".to_string());
+    }
+
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lower_case() -> LintConfig {
+        LintConfig {
+            subject_max_length: None,
+            subject_case: SubjectCase::Lower,
+            require_type: false,
+            require_body: false,
+        }
+    }
+
+    #[test]
+    fn lowercase_subjects_may_start_with_an_acronym() {
+        let config = lower_case();
+        assert!(lint_message(&config, "HTTP client retries on 503").is_empty());
+        assert!(lint_message(&config, "fix: README typo").is_empty());
+        assert_eq!(lint_message(&config, "fix: Handle empty input").len(), 1);
+        assert_eq!(lint_message(&config, "A new parser").len(), 1);
+    }
+}
@@ -1,10 +1,13 @@
 mod config;
+mod error;
 mod git;
+mod jobs;
+mod lint;
 mod ollama;
 mod utils;
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
 use colored::*;
 use std::io::Write;
 use std::path::PathBuf;
@@ -12,26 +15,51 @@ use std::path::PathBuf;
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short, long)]
     config: Option<PathBuf>,
 
-    #[arg(short, long)]
+    #[arg(long, env = "COMMIT_GEN_CONFIG_DIR")]
+    config_dir: Option<PathBuf>,
+
+    // --background adds --yes for the job, so it may already be there
+    #[arg(short, long, overrides_with = "yes")]
     yes: bool,
 
     #[arg(short, long)]
     diff: bool,
 
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    #[arg(long)]
+    debug_xml: bool,
+
     #[arg(short, long)]
-    verbose: bool,
+    quiet: bool,
+
+    #[arg(long)]
+    pager: bool,
 
     #[arg(short = 'x', long)]
     xml: bool,
 
-    #[arg(short = 'i', long)]
-    issue: Option<u32>,
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    #[arg(short = 'i', long, value_name = "NUMBER")]
+    issue: Vec<String>,
 
-    #[arg(short = 'p', long)]
-    pr: Option<u32>,
+    #[arg(short = 'p', long, value_name = "NUMBER")]
+    pr: Vec<String>,
+
+    #[arg(long)]
+    no_footer: bool,
+
+    #[arg(long, value_name = "KEY: VALUE", value_parser = parse_trailer)]
+    trailer: Vec<String>,
 
     #[arg(long)]
     date: Option<String>,
@@ -43,24 +71,716 @@ struct Cli {
     committer_date: Option<String>,
 
     #[arg(long)]
+    date_base: Option<String>,
+
+    #[arg(long)]
+    amend: bool,
+
+    #[arg(long, requires = "amend", conflicts_with_all = ["patch", "since", "base"])]
+    reword_only: bool,
+
+    #[arg(long)]
+    allow_empty: bool,
+
+    #[arg(long)]
+    allow_empty_message: bool,
+
+    #[arg(long)]
+    allow_protected: bool,
+
+    #[arg(long, value_name = "REF")]
+    since: Option<String>,
+
+    #[arg(long, value_name = "BASE", conflicts_with = "since")]
+    pr_summary: Option<String>,
+
+    #[arg(long, value_name = "REF", conflicts_with_all = ["since", "pr_summary", "patch"])]
+    base: Option<String>,
+
+    #[arg(long, conflicts_with_all = ["since", "pr_summary", "base", "patch", "staged", "amend"])]
+    since_last_tag: bool,
+
+    #[arg(short = 'm', long, conflicts_with = "wip")]
+    message: Option<String>,
+
+    #[arg(long)]
+    wip: bool,
+
+    #[arg(long, conflicts_with = "since")]
+    patch: bool,
+
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["since", "base", "pr_summary", "patch", "amend", "message", "wip", "background"])]
+    git_editor: Option<PathBuf>,
+
+    #[arg(long)]
+    explain_selection: bool,
+
+    #[arg(short = 'e', long, conflicts_with_all = ["git_editor", "compare"])]
+    edit: bool,
+
+    #[arg(long)]
+    edit_verbose: bool,
+
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["since", "base", "pr_summary", "since_last_tag", "git_editor", "message", "wip", "background"])]
+    compare: Option<PathBuf>,
+
+    #[arg(long, requires = "compare")]
+    critique: bool,
+
+    #[arg(long, conflicts_with_all = ["since", "base", "reword_only"])]
+    staged: bool,
+
+    #[arg(long, value_name = "NAME")]
+    retry_model: Vec<String>,
+
+    #[arg(long)]
+    stat_only: bool,
+
+    #[arg(long)]
+    context_lines_per_file: bool,
+
+    #[arg(long)]
+    strip_comments_from_diff: bool,
+
+    #[arg(long)]
+    include_diff_in_body: bool,
+
+    #[arg(long)]
+    print_config: bool,
+
+    #[arg(long)]
+    show_tokens: bool,
+
+    #[arg(long)]
+    background: bool,
+
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    #[arg(long, value_name = "ID")]
+    result: Option<String>,
+
+    #[arg(long, hide = true)]
+    job: Option<String>,
+
+    #[arg(long, overrides_with = "no_emoji")]
+    emoji: bool,
+
+    #[arg(long, overrides_with = "emoji")]
+    no_emoji: bool,
+
+    #[arg(long, overrides_with = "no_conventional")]
+    conventional: bool,
+
+    #[arg(long, overrides_with = "conventional")]
+    no_conventional: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the models on the configured Ollama server: name, size, last modified
+    Models,
+    /// Ask the model whether a commit's message describes its changes; nothing is changed
+    Review {
+        #[arg(value_name = "REV")]
+        rev: String,
+    },
+}
+
+// exit codes, stable for scripts: 0 when the run finished (committed, or printed what was
+// asked for), 1 for any other error
+const EXIT_ABORTED: i32 = 3; // declined at a prompt
+const EXIT_NO_CHANGES: i32 = 4; // nothing to commit
+const EXIT_MODEL_FAILURE: i32 = 5; // the Ollama request itself failed
+const EXIT_TIMEOUT: i32 = 6; // --timeout ran out
+
+// set once the commit starts being written; --timeout lets it finish from then on, so a
+// commit is never left half done
+static COMMIT_STARTED: std::sync::Mutex<bool> = std::sync::Mutex::new(false);
+
+// --timeout as a hard limit: much of a run blocks (git, prompts, the editor, the pager),
+// where a timeout on the future would never get a chance to fire
+fn start_watchdog(secs: u64, job: Option<String>) {
+    std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_secs(secs));
+        let started = COMMIT_STARTED.lock().unwrap_or_else(|e| e.into_inner());
+        if *started {
+            return;
+        }
+        let error = anyhow::Error::from(error::CommitGenError::TimedOut(secs));
+        if let Some(id) = &job {
+            let _ = jobs::write_error(id, &error);
+        }
+        eprintln!("Error: {}", error);
+        std::process::exit(EXIT_TIMEOUT);
+    });
+}
+
+enum Outcome {
+    Finished,
+    Aborted,
+    NoChanges,
+}
+
+fn ask(prompt: &str) -> Result<String> {
+    print!("{}", prompt.cyan());
+    std::io::stdout().flush()?;
+
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+
+    Ok(input.trim().to_lowercase())
+}
+
+// an empty answer takes the default; anything that isn't a yes counts as no,
+// so a typo never commits
+fn is_yes(answer: &str, default: bool) -> bool {
+    match answer.trim().to_lowercase().as_str() {
+        "" => default,
+        "y" | "yes" => true,
+        _ => false,
+    }
+}
+
+// the capital letter in the hint is what a bare Enter picks
+fn confirm(prompt: &str, default: bool) -> Result<bool> {
+    let hint = if default { "[Y/n]" } else { "[y/N]" };
+    Ok(is_yes(&ask(&format!("{} {} ", prompt, hint))?, default))
+}
+
+// the most similar of the last commit.dedupe_commits subjects, if it passes the threshold;
+// an amended commit isn't compared with itself
+fn near_duplicate(commit: &config::CommitConfig, subject: &str, amend: bool) -> Result<Option<String>> {
+    if commit.dedupe_commits == 0 {
+        return Ok(None);
+    }
+    let recent = git::recent_subjects(commit.dedupe_commits + amend as usize)?;
+    Ok(recent.into_iter()
+        .skip(amend as usize)
+        .map(|previous| (utils::similarity(subject, &previous), previous))
+        .filter(|(score, _)| *score >= commit.dedupe_threshold)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, previous)| previous))
+}
+
+// like `git commit` without -m: the message and a commented-out summary of the commit in
+// .git/COMMIT_EDITMSG, returning what is left once the comments are stripped. verbose adds
+// the diff below a scissors line, which read_editmsg drops along with everything after it
+fn edit_message(message: &str, stage_all: bool, reword_only: bool, verbose: bool, stage_exclude: &[String]) -> Result<String> {
+    let comment_char = git::comment_char();
+    let mut content = format!(
+        "{}\n\n{c} Please enter the commit message for your changes. Lines starting\n\
+        {c} with '{c}' will be ignored, and an empty message aborts the commit.\n",
+        message,
+        c = comment_char
+    );
+    if !reword_only {
+        content.push_str(&format!("{c}\n{c} {}\n{c}\n{c} Changes to be committed:\n", git::get_diff_stat(stage_all, stage_exclude)?, c = comment_char));
+        for (status, path) in git::get_files_to_commit(stage_all, stage_exclude)? {
+            content.push_str(&format!("{}\t{}: {}\n", comment_char, status, path));
+        }
+        if verbose {
+            content.push_str(&format!(
+                "{c} ------------------------ >8 ------------------------\n\
+                {c} Do not modify or remove the line above.\n\
+                {c} Everything below it will be ignored.\n{}",
+                git::get_commit_patch(stage_all, stage_exclude)?,
+                c = comment_char
+            ));
+        }
+    }
+
+    let path = git::editmsg_path()?;
+    std::fs::write(&path, content)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    utils::edit_file(&git::editor(), &path)?;
+    Ok(utils::read_editmsg(&path, &comment_char)?.text)
+}
+
+// every changed file with what the heuristic ranks it by (extension rank, src/, diff
+// size) and whether the model or the heuristic picked it, or why it was left out
+fn print_selection_report(generation: &ollama::Generation) {
+    if generation.selection.is_empty() {
+        return;
+    }
+    println!("\n{}", "Selection report:".blue().bold());
+    let width = generation.selection.iter().map(|candidate| candidate.path.len()).max().unwrap_or(0);
+    for candidate in &generation.selection {
+        let (label, note) = match candidate.outcome {
+            ollama::SelectionOutcome::Model => ("model".green(), ""),
+            ollama::SelectionOutcome::Heuristic => ("heuristic".cyan(), ""),
+            ollama::SelectionOutcome::Skipped(reason) => ("skipped".dimmed(), reason),
+        };
+        let line = format!(
+            "  {:<9}  {:<width$}  {:>5} lines  {:>7} bytes  ext {}  {:<3}  {}",
+            label,
+            candidate.path,
+            candidate.line_count,
+            candidate.diff_bytes,
+            candidate.extension_rank,
+            if candidate.in_src { "src" } else { "" },
+            note,
+        );
+        println!("{}", line.trim_end());
+    }
+}
+
+fn print_diff(diff: &str) {
+    for line in diff.lines() {
+        match line.chars().next() {
+            Some('+') => println!("{}", line.green()),
+            Some('-') => println!("{}", line.red()),
+            Some('@') => println!("{}", line.cyan()),
+            _ => println!("{}", line),
+        }
+    }
+}
+
+// what goes around the generated message, worked out once before generating
+#[derive(Default)]
+struct Additions {
+    ticket: Option<(String, String)>,
+    max_subject_length: Option<usize>,
+    diff_stat: Option<String>,
+    references: Vec<String>,
+    trailers: Vec<String>,
     amend: bool,
 }
 
+// the generated message as it will be committed: rewrites, the subject's ticket and
+// length limit, the diffstat, references and trailers. lint checks this, not what the
+// model wrote
+fn assemble_message(message: &str, config: &config::Config, additions: &Additions) -> Result<String> {
+    let mut message = utils::apply_rewrites(message, &config.rewrites)?;
+    if let Some((ticket, format)) = &additions.ticket {
+        message = ollama::prefix_ticket(&message, ticket, format);
+    }
+    if let Some(max) = additions.max_subject_length {
+        message = ollama::limit_subject(&message, max);
+    }
+    if let Some(diff_stat) = &additions.diff_stat {
+        message = format!("{}\n\n{}", message, diff_stat);
+    }
+    if !additions.references.is_empty() {
+        message = format!("{}\n\n{}", message, additions.references.join("\n"));
+    }
+    let mut trailers = additions.trailers.clone();
+    if config.commit.gerrit_change_id && !message.contains("Change-Id: ") && !trailers.iter().any(|t| t.starts_with("Change-Id: ")) {
+        trailers.push(format!("Change-Id: {}", git::change_id(&message, additions.amend)?));
+    }
+    Ok(utils::append_trailers(&message, &trailers))
+}
+
+// "Key: Value" or "Key=Value", normalized to the former
+fn parse_trailer(trailer: &str) -> Result<String, String> {
+    let (key, value) = trailer.split_once(':')
+        .or_else(|| trailer.split_once('='))
+        .ok_or_else(|| format!("'{}' is not of the form 'Key: Value'", trailer))?;
+    let (key, value) = (key.trim(), value.trim());
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(format!("'{}' is not a valid trailer key (letters, digits and '-' only)", key));
+    }
+    if value.is_empty() || value.contains('\n') {
+        return Err(format!("trailer '{}' needs a single-line value", key));
+    }
+    Ok(format!("{}: {}", key, value))
+}
+
+fn print_token_usage(exchanges: &[ollama::Exchange], num_ctx: Option<u32>) {
+    let num_ctx = num_ctx.unwrap_or(ollama::DEFAULT_NUM_CTX);
+    println!("\n{}", "Token usage:".blue().bold());
+    for exchange in exchanges {
+        match (exchange.prompt_tokens, exchange.response_tokens) {
+            (Some(prompt), Some(response)) => {
+                println!("  {} ({}): {} prompt + {} response tokens", exchange.stage, exchange.model, prompt, response);
+                // Ollama silently drops the start of a prompt that doesn't fit
+                if prompt >= num_ctx / 10 * 9 {
+                    println!("  {}", format!(
+                        "Warning: {} of the {} token context window; lower formatting.max_diff_lines or selection.max_files, or raise model.num_ctx",
+                        prompt, num_ctx
+                    ).yellow());
+                }
+            }
+            _ => println!("  {} ({}): not reported", exchange.stage, exchange.model),
+        }
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < units.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+fn explain_selection(generation: &ollama::Generation, stage_all: bool, stage_exclude: &[String]) -> Result<()> {
+    if generation.selected_files.is_empty() {
+        println!("\n{}", "No file selection was made for this message.".yellow());
+    } else {
+        println!("\n{}", "Files examined in detail:".blue().bold());
+        for file in &generation.selected_files {
+            println!("  - {}", file);
+        }
+        println!("\n{}", "Model's file selection response:".blue().bold());
+        println!("{}", generation.selection_response);
+    }
+    print_selection_report(generation);
+    println!("\n{}", "Included in the commit:".blue().bold());
+    println!("{}", git::get_diff_stat(stage_all, stage_exclude)?);
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cli = Cli::parse();
-    
-    let config = utils::load_config(cli.config)?;
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            // a background job that can't start has nobody to show the usage error to
+            if let Some(id) = jobs::id_from_args() {
+                jobs::write_error(&id, &anyhow::anyhow!("{}", e.render()))?;
+            }
+            e.exit()
+        }
+    };
+
+    if let Some(id) = &cli.result {
+        return match jobs::read_result(id)? {
+            jobs::JobStatus::Done(message) => {
+                println!("{}", message);
+                Ok(())
+            }
+            jobs::JobStatus::Failed(error) => Err(anyhow::anyhow!("Background job {} failed: {}", id, error)),
+            jobs::JobStatus::Ended(status) => {
+                println!("{}", format!("Job {} finished without a message: {}.", id, status).yellow());
+                Ok(())
+            }
+            jobs::JobStatus::Pending => {
+                println!("{}", format!("Job {} is still running.", id).yellow());
+                Ok(())
+            }
+        };
+    }
+
+    if cli.background {
+        let id = jobs::spawn()?;
+        println!("{} {}", "Started background job".green(), id.bold());
+        println!("Run `commit-gen --result {}` to get the message.", id);
+        return Ok(());
+    }
+
+    let job = cli.job.clone();
+    if let Some(secs) = cli.timeout {
+        start_watchdog(secs, job.clone());
+    }
+    let result = run(cli).await;
+    // every outcome leaves a file behind, or --result would report the job as running forever
+    if let Some(id) = &job {
+        match &result {
+            Err(e) => jobs::write_error(id, e)?,
+            Ok(Outcome::Aborted) => jobs::write_status(id, "aborted")?,
+            Ok(Outcome::NoChanges) => jobs::write_status(id, "no changes")?,
+            // a generated message has already been written as the result
+            Ok(Outcome::Finished) => jobs::write_status(id, "finished")?,
+        }
+    }
+    // these already say what to do, whichever step ran into them
+    let server_problem = result.as_ref().err().and_then(|e| e.chain().find_map(|cause| match cause.downcast_ref() {
+        Some(e @ (error::CommitGenError::OllamaUnreachable { .. } | error::CommitGenError::ModelNotPulled(_))) => Some(e.to_string()),
+        _ => None,
+    }));
+    if let Some(problem) = server_problem {
+        eprintln!("Error: {}", problem);
+        std::process::exit(EXIT_MODEL_FAILURE);
+    }
+    match result {
+        Ok(Outcome::Finished) => Ok(()),
+        Ok(Outcome::Aborted) => std::process::exit(EXIT_ABORTED),
+        Ok(Outcome::NoChanges) => std::process::exit(EXIT_NO_CHANGES),
+        Err(e) if e.chain().any(|cause| {
+            cause.is::<ollama_rs::error::OllamaError>()
+                || cause.downcast_ref::<error::CommitGenError>().is_some_and(error::CommitGenError::is_model_failure)
+        }) => {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(EXIT_MODEL_FAILURE)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn run(cli: Cli) -> Result<Outcome> {
+    let mut config = utils::load_config(cli.config, cli.config_dir)?;
+    if let Some(root) = git::repo_root() {
+        utils::apply_repo_prompts(&mut config, &root)?;
+    }
+
+    if cli.emoji || cli.no_emoji {
+        config.commit.emoji = cli.emoji;
+    }
+    if cli.conventional || cli.no_conventional {
+        config.commit.conventional = cli.conventional;
+    }
+    if cli.stat_only {
+        config.formatting.stat_only_threshold = Some(0);
+    }
+    if cli.context_lines_per_file {
+        config.formatting.adaptive_context = true;
+    }
+    if cli.strip_comments_from_diff {
+        config.formatting.strip_comments = true;
+    }
+    if cli.include_diff_in_body {
+        config.commit.include_diff_in_body = true;
+    }
+    if !cli.retry_model.is_empty() {
+        config.model.fallback_models = cli.retry_model.clone();
+    }
+
+    if cli.print_config {
+        print!("{}", toml::to_string_pretty(&config)?);
+        return Ok(Outcome::Finished);
+    }
+    if let Some(Command::Models) = &cli.command {
+        let mut models = ollama::list_models(&config.model).await?;
+        models.sort_by(|a, b| a.name.cmp(&b.name));
+        let width = models.iter().map(|model| model.name.len()).max().unwrap_or(0).max(4);
+        println!("{}", format!("{:<width$}  {:>9}  MODIFIED", "NAME", "SIZE").bold());
+        for model in &models {
+            println!("{:<width$}  {:>9}  {}", model.name, format_size(model.size), model.modified_at);
+        }
+        return Ok(Outcome::Finished);
+    }
+    config.prompts.validate()?;
+
+    let verbosity = ollama::Verbosity {
+        level: cli.verbose,
+        debug_xml: cli.debug_xml,
+    };
+
+    if let Some(base) = &cli.pr_summary {
+        let changes = git::get_changes_since(base)?;
+        if changes.is_empty() {
+            println!("{}", format!("No commits since {}!", base).yellow());
+            return Ok(Outcome::NoChanges);
+        }
+        let subjects = git::get_commit_subjects_since(base)?;
+        let mut session = ollama::Session::new(&config.model, verbosity);
+        let summary = ollama::generate_pr_summary(&mut session, &config, &changes, &subjects, verbosity).await?;
+        println!("{}\n\n{}", summary.title, summary.body);
+        return Ok(Outcome::Finished);
+    }
+
+    if cli.since_last_tag {
+        let tag = git::last_tag()?;
+        let changes = git::get_changes_since(&tag)?;
+        if changes.is_empty() {
+            println!("{}", format!("No commits since {}!", tag).yellow());
+            return Ok(Outcome::NoChanges);
+        }
+        let subjects = git::get_commit_subjects_since(&tag)?;
+        let mut session = ollama::Session::new(&config.model, verbosity);
+        let notes = ollama::generate_release_notes(&mut session, &config, &changes, &subjects, &tag, verbosity).await?;
+        println!("{}", notes);
+        return Ok(Outcome::Finished);
+    }
+
+    if let Some(Command::Review { rev }) = &cli.command {
+        let changes = git::get_commit_changes(rev)?;
+        let message = git::get_commit_message(rev)?;
+        let mut session = ollama::Session::new(&config.model, verbosity);
+        let review = ollama::review_commit(&mut session, &config, &changes, &message, verbosity).await?;
+        println!("{}\n{}\n", "Commit message:".blue().bold(), message);
+        println!("{}\n{}", "Review:".green().bold(), review);
+        return Ok(Outcome::Finished);
+    }
     
-    let git_changes = git::get_changes(&config.git)?;
+    // as git's editor, git has already built the index it is about to commit
+    let editmsg = match &cli.git_editor {
+        Some(path) => Some(utils::read_editmsg(path, &git::comment_char())?),
+        None => None,
+    };
+    if let Some(text) = editmsg.as_ref().map(|editmsg| &editmsg.text).filter(|text| !text.is_empty()) {
+        config.prompts.commit_context.push_str(&format!(
+            "\n\nThe author has started the message; keep its intent and wording where it fits:\n{}",
+            text
+        ));
+    }
+
+    // --patch builds the commit in the index and --staged and --git-editor take the index
+    // as it is, so everything after works from it alone. the same goes for leaving unstaged
+    // changes out of the prompt, or the commit would hold changes the message never saw
+    let stage_all = !cli.patch && !cli.staged && cli.git_editor.is_none() && config.git.include_unstaged;
+    if !stage_all {
+        config.git.include_staged = true;
+        config.git.include_unstaged = false;
+    }
+    if cli.patch {
+        let staged = git::stage_hunks(|path, hunk| {
+            println!("\n{}", path.bold());
+            print_diff(hunk);
+            Ok(match ask("Stage this hunk? [y/n/q] ")?.as_str() {
+                "y" => Some(true),
+                "q" => None,
+                _ => Some(false),
+            })
+        })?;
+        println!("{}", format!("Staged {} hunk(s).", staged).green());
+    }
+
+    let git_changes = match (&cli.since, &cli.base) {
+        (Some(since), _) => git::get_changes_since(since)?,
+        (None, Some(base)) => git::get_changes_against(base)?,
+        (None, None) if cli.reword_only => git::get_commit_changes("HEAD")?,
+        (None, None) => git::get_changes(&config.git, &config.formatting)?,
+    };
     
     if git_changes.is_empty() {
         println!("{}", "No changes to commit!".yellow());
-        return Ok(());
+        return Ok(Outcome::NoChanges);
     }
+
+    // selection.include_extensions narrows what the model is told about, not the commit
+    let mut prompt_changes = git_changes.with_extensions(&config.selection.include_extensions);
+    if prompt_changes.is_empty() {
+        println!("{}", "Warning: no changed file matches selection.include_extensions, describing them all".yellow());
+        prompt_changes = git_changes.clone();
+    }
+
+    // checked before generating, so a refused commit doesn't cost a model call
+    let commits = cli.since.is_none() && cli.base.is_none() && cli.job.is_none() && cli.compare.is_none();
+    if let Some(branch) = git::current_branch().filter(|branch| commits && config.git.protected_branches.contains(branch)) {
+        if cli.yes && !cli.allow_protected {
+            return Err(error::CommitGenError::ProtectedBranch(branch).into());
+        }
+        if !cli.yes && !confirm(&format!("'{}' is a protected branch. Commit to it anyway?", branch), false)? {
+            println!("{}", "Commit aborted.".yellow());
+            return Ok(Outcome::Aborted);
+        }
+    }
+
+    let ticket = match &config.commit.subject_ticket_prefix {
+        Some(format) => {
+            let ticket_re = regex::Regex::new(&config.commit.ticket_pattern)
+                .context("Invalid commit.ticket_pattern")?;
+            cli.issue.first().cloned()
+                .or_else(|| git::current_branch().and_then(|branch| ticket_re.find(&branch).map(|m| m.as_str().to_string())))
+                .map(|ticket| (ticket, format.clone()))
+        }
+        None => None,
+    };
+
+    // --since and --base don't commit, so there's no diffstat of the commit to give
+    let diff_stat = if config.commit.include_diff_in_body && cli.since.is_none() && cli.base.is_none() {
+        Some(if cli.reword_only {
+            git::get_commit_diff_stat("HEAD")?
+        } else {
+            git::get_diff_stat(stage_all, &config.git.stage_exclude)?
+        })
+    } else {
+        None
+    };
+    // --issue still feeds commit.subject_ticket_prefix
+    let references = if cli.no_footer {
+        Vec::new()
+    } else {
+        cli.issue.iter()
+            .map(|issue| config.commit.issue_footer_format.replace("{number}", issue))
+            .chain(cli.pr.iter().map(|pr| config.commit.pr_footer_format.replace("{number}", pr)))
+            .collect()
+    };
+    let mut trailers = Vec::new();
+    for trailer in &cli.trailer {
+        // parse_trailer guarantees the "Key: " prefix
+        let (key, value) = trailer.split_once(": ").unwrap_or((trailer, ""));
+        trailers.push(format!("{}: {}", key, git::mailmap_identity(value)?));
+    }
+    let additions = Additions {
+        ticket,
+        // a subject given with -m is committed as written
+        max_subject_length: config.commit.max_subject_length.filter(|_| cli.message.is_none()),
+        diff_stat: diff_stat.map(|diff_stat| diff_stat.to_string()),
+        references,
+        trailers,
+        amend: cli.amend,
+    };
+
+    // every attempt's exchanges end up in the log, not just the accepted one
+    let mut exchanges = Vec::new();
+    let mut session = ollama::Session::new(&config.model, verbosity);
+    let mut regenerated_duplicate = false;
+    let (generation, mut final_message) = if let Some(message) = &cli.message {
+        let generation = ollama::Generation::from_message(message.clone());
+        let message = assemble_message(&generation.message, &config, &additions)?;
+        (generation, message)
+    } else if cli.wip {
+        let generation = ollama::Generation::from_message(git_changes.wip_message());
+        let message = assemble_message(&generation.message, &config, &additions)?;
+        (generation, message)
+    } else {
+        loop {
+            let mut generation = match ollama::generate_with_fallback(&mut session, &mut config, &prompt_changes, verbosity).await.map_err(anyhow::Error::from) {
+                Ok(generation) => generation,
+                Err(e) if config.model.fallback_on_error => {
+                    println!("{} {:#}", "Warning: generation failed, falling back to a template message:".yellow(), e);
+                    let generation = ollama::Generation::from_message(git_changes.fallback_message());
+                    let message = assemble_message(&generation.message, &config, &additions)?;
+                    break (generation, message);
+                }
+                Err(e) => return Err(e),
+            };
+            exchanges.append(&mut generation.exchanges);
+
+            let message = assemble_message(&generation.message, &config, &additions)?;
+            let violations = lint::lint_message(&config.lint, &message);
+            if violations.is_empty() {
+                let subject = message.lines().next().unwrap_or("");
+                let Some(previous) = near_duplicate(&config.commit, subject, cli.amend)? else {
+                    break (generation, message);
+                };
+                if config.commit.on_duplicate == config::DuplicateAction::Regenerate && !regenerated_duplicate {
+                    println!("{} {}", "Subject is nearly the same as a recent commit's, regenerating:".yellow(), previous);
+                    config.prompts.commit_context.push_str(&format!(
+                        "\n\nA recent commit is titled \"{}\". Write a subject that makes clear how this change differs from it.",
+                        previous
+                    ));
+                    regenerated_duplicate = true;
+                    continue;
+                }
+                println!("{} {}", "Warning: the subject is nearly the same as a recent commit's:".yellow(), previous);
+                break (generation, message);
+            }
+
+            println!("\n{}", "Generated commit message failed lint:".red().bold());
+            println!("{}\n", message);
+            for violation in &violations {
+                println!("  - {}", violation);
+            }
+
+            if cli.yes {
+                return Err(error::CommitGenError::LintFailed(violations.len()).into());
+            }
+            if !confirm("\nDo you want to regenerate the message?", config.commit.default_confirm)? {
+                println!("{}", "Commit aborted.".yellow());
+                return Ok(Outcome::Aborted);
+            }
+        }
+    };
     
-    let (commit_message, raw_xml) = ollama::generate_commit_message(&config, &git_changes, cli.verbose).await?;
-    
+    if cli.explain_selection {
+        print_selection_report(&generation);
+    }
+
+    if cli.show_tokens && !exchanges.is_empty() {
+        print_token_usage(&exchanges, config.model.num_ctx);
+    }
+
     if cli.diff {
         println!("\n{}", "Changes:".green().bold());
         println!("{}", git_changes);
@@ -68,45 +788,191 @@ async fn main() -> Result<()> {
 
     if cli.xml {
         println!("\n{}", "Raw XML Response:".blue().bold());
-        println!("{}", raw_xml);
+        println!("{}", generation.raw_response);
     }
     
     println!("\n{}", "Generated Commit Message:".green().bold());
-    let mut final_message = commit_message;
+    println!("{}", final_message);
+
+    if let Some(log_file) = cli.log_file.as_ref().or(config.log.file.as_ref()) {
+        let record = serde_json::json!({
+            "timestamp": chrono::Local::now().to_rfc3339(),
+            "model": config.model.name,
+            "exchanges": exchanges,
+            "final_message": final_message,
+        });
+        utils::append_log(log_file, &record)?;
+    }
 
-    let mut references = Vec::new();
-    if let Some(issue) = cli.issue {
-        references.push(format!("Fixes issue #{}", issue));
+    // a dry run: the draft is read like a message git would commit, comments dropped
+    if let Some(path) = &cli.compare {
+        let draft = utils::read_editmsg(path, &git::comment_char())?.text;
+        println!("\n{}", "Draft vs generated:".blue().bold());
+        print_diff(&git::diff_text(&format!("{}\n", draft), &format!("{}\n", final_message), "draft", "generated")?);
+        if cli.critique {
+            let critique = ollama::compare_messages(&mut session, &config, &prompt_changes, &generation, &final_message, &draft, verbosity).await?;
+            println!("\n{}\n{}", "Critique:".green().bold(), critique);
+        }
+        return Ok(Outcome::Finished);
     }
-    if let Some(pr) = cli.pr {
-        references.push(format!("Related to PR #{}", pr));
+
+    if let Some(id) = &cli.job {
+        jobs::write_result(id, &final_message)?;
+        return Ok(Outcome::Finished);
     }
-    if !references.is_empty() {
-        final_message = format!("{}\n\n{}", final_message, references.join("\n"));
+
+    // --since and --base describe a diff that isn't what the commit would contain
+    if cli.since.is_some() || cli.base.is_some() {
+        return Ok(Outcome::Finished);
     }
 
-    println!("{}", final_message);
+    let markers = git_changes.marker_counts(&config.git.warn_markers);
+    if !markers.is_empty() {
+        println!("\n{} {}", "Warning: the commit adds lines containing".yellow(), config.git.warn_markers.join("/").yellow());
+        for (path, count) in markers {
+            println!("  {} ({})", path, count);
+        }
+    }
+
+    // exclude_patterns keeps files out of the prompt, not out of the commit
+    if !cli.reword_only {
+        let undescribed: Vec<_> = git::get_files_to_commit(stage_all, &config.git.stage_exclude)?
+            .into_iter()
+            .filter(|(_, path)| git::is_excluded(path, &config.git.exclude_patterns))
+            .collect();
+        if !undescribed.is_empty() {
+            println!("\n{}", "Warning: committing files the message doesn't describe (git.exclude_patterns):".yellow().bold());
+            for (status, path) in undescribed {
+                println!("  {}: {}", status, path);
+            }
+        }
+    }
+
+    if cli.pager && !cli.yes {
+        let mut preview = final_message.clone();
+        if !cli.reword_only {
+            preview.push_str(&format!("\n\n{}", git::get_diff_stat(stage_all, &config.git.stage_exclude)?));
+        }
+        if cli.diff {
+            preview.push_str(&format!("\n\n{}", git_changes));
+        }
+        utils::show_in_pager(&git::pager(), &preview)?;
+    } else if !cli.yes && !cli.quiet && !cli.reword_only {
+        let diff_stat = git::get_diff_stat(stage_all, &config.git.stage_exclude)?;
+        println!("\n{}", diff_stat.to_string().bold());
+    }
     
-    if !cli.yes {
-        print!("\n{}", "Do you want to commit with this message? [Y/n] ".cyan());
-        std::io::stdout().flush()?;
-        
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input)?;
-        
-        if input.trim().to_lowercase() != "y" {
-            println!("{}", "Commit aborted.".yellow());
-            return Ok(());
+    if !cli.yes && cli.git_editor.is_none() && (cli.edit || config.commit.always_edit) {
+        let verbose = cli.edit_verbose || config.commit.edit_verbose;
+        final_message = edit_message(&final_message, stage_all, cli.reword_only, verbose, &config.git.stage_exclude)?;
+        if final_message.is_empty() && !cli.allow_empty_message {
+            println!("{}", "Aborting commit due to empty commit message.".yellow());
+            return Ok(Outcome::Aborted);
+        }
+    } else if !cli.yes {
+        let hint = if config.commit.default_confirm { "[Y/n/e(xplain)/s(ubject)]" } else { "[y/N/e(xplain)/s(ubject)]" };
+        loop {
+            match ask(&format!("\nDo you want to commit with this message? {} ", hint))?.as_str() {
+                "e" | "explain" => explain_selection(&generation, stage_all, &config.git.stage_exclude)?,
+                // everything after the subject (body, references, trailers) stays as it is
+                "s" | "subject" => {
+                    let subject = ollama::reroll_subject(&mut session, &config, &prompt_changes, &generation, verbosity).await?;
+                    // a lone subject gets the rewrites, the ticket and the length limit, nothing else
+                    let subject = assemble_message(&subject, &config, &Additions {
+                        ticket: additions.ticket.clone(),
+                        max_subject_length: config.commit.max_subject_length,
+                        ..Default::default()
+                    })?;
+                    let rest = final_message.split_once('\n').map_or("", |(_, rest)| rest);
+                    let rerolled = if rest.is_empty() { subject } else { format!("{}\n{}", subject, rest) };
+                    let violations = lint::lint_message(&config.lint, &rerolled);
+                    if !violations.is_empty() {
+                        println!("\n{}", "The new subject failed lint, keeping the previous one:".red().bold());
+                        println!("{}\n", rerolled.lines().next().unwrap_or(""));
+                        for violation in &violations {
+                            println!("  - {}", violation);
+                        }
+                        continue;
+                    }
+                    final_message = rerolled;
+                    println!("\n{}", "Generated Commit Message:".green().bold());
+                    println!("{}", final_message);
+                }
+                answer if is_yes(answer, config.commit.default_confirm) => break,
+                _ => {
+                    println!("{}", "Commit aborted.".yellow());
+                    return Ok(Outcome::Aborted);
+                }
+            }
         }
     }
     
-    git::create_commit(
-        &final_message, 
-        cli.date.as_deref(),
-        cli.author_date.as_deref(),
-        cli.committer_date.as_deref(),
-        cli.amend,
-    )?;
+    // git makes the commit once its editor (us) exits successfully
+    if let (Some(path), Some(editmsg)) = (&cli.git_editor, &editmsg) {
+        let mut content = format!("{}\n", final_message);
+        if !editmsg.tail.is_empty() {
+            content.push_str(&format!("\n{}\n", editmsg.tail));
+        }
+        std::fs::write(path, content)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        return Ok(Outcome::Finished);
+    }
+
+    // under --yes nobody sees a diffstat, so leave a record of what went in
+    if cli.yes && config.commit.confirm_files && !cli.reword_only {
+        println!("\n{}", "Committing:".blue().bold());
+        for (status, path) in git::get_files_to_commit(stage_all, &config.git.stage_exclude)? {
+            println!("  {}: {}", status, path);
+        }
+    }
+
+    *COMMIT_STARTED.lock().unwrap_or_else(|e| e.into_inner()) = true;
+    let commit_id = git::create_commit(&final_message, &git::CommitOptions {
+        date: cli.date.as_deref(),
+        author_date: cli.author_date.as_deref(),
+        committer_date: cli.committer_date.as_deref(),
+        date_base: cli.date_base.as_deref(),
+        amend: cli.amend,
+        allow_empty: cli.allow_empty,
+        allow_empty_message: cli.allow_empty_message,
+        stage_exclude: &config.git.stage_exclude,
+        stage_all,
+        reword_only: cli.reword_only,
+    })?;
+
+    if let Some(command) = &config.commit.post_commit_command {
+        let subject = final_message.lines().next().unwrap_or("");
+        if let Err(e) = utils::run_post_commit_command(command, &commit_id.to_string(), subject) {
+            println!("{} {}", "Warning: post-commit command failed:".yellow(), e);
+        }
+    }
     
-    Ok(())
+    Ok(Outcome::Finished)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_yes_accepts_y_and_yes_in_any_case() {
+        for answer in ["y", "Y", "yes", "Yes", "YES", " y ", "\tyes\n"] {
+            assert!(is_yes(answer, false), "{:?}", answer);
+        }
+    }
+
+    #[test]
+    fn is_yes_rejects_anything_else() {
+        for answer in ["n", "no", "NO", "yep", "ye s", "q"] {
+            assert!(!is_yes(answer, true), "{:?}", answer);
+        }
+    }
+
+    #[test]
+    fn is_yes_uses_the_default_for_a_blank_answer() {
+        assert!(is_yes("", true));
+        assert!(is_yes("   ", true));
+        assert!(!is_yes("", false));
+        assert!(!is_yes(" \n", false));
+    }
 }
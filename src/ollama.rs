@@ -1,14 +1,571 @@
-use anyhow::{Context, Result};
+use anyhow::Context;
 use ollama_rs::{
     generation::{
         completion::request::GenerationRequest,
         options::GenerationOptions,
+        parameters::{FormatType, KeepAlive, TimeUnit},
     },
+    error::OllamaError,
+    models::LocalModel,
     Ollama,
 };
-use std::collections::HashSet;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 
-use crate::{config::Config, git::GitChanges};
+use crate::{config::{CommitConfig, Config, EmojiPosition, FileSelectionConfig, FormattingConfig, ModelConfig, ResponseFormat, SubjectCase, TagsConfig}, git::{self, FileChange, GitChanges}, error::{CommitGenError, Result}, lint::starts_with_acronym};
+
+#[derive(Serialize)]
+pub struct Exchange {
+    pub stage: &'static str,
+    pub model: String,
+    pub system: String,
+    pub prompt: String,
+    pub response: String,
+    // as counted by the model's tokenizer, when Ollama reports them
+    pub prompt_tokens: Option<u32>,
+    pub response_tokens: Option<u32>,
+}
+
+// -v shows the steps taken, -vv adds the full prompts, --debug-xml shows raw responses and parsing
+#[derive(Clone, Copy, Default)]
+pub struct Verbosity {
+    pub level: u8,
+    pub debug_xml: bool,
+}
+
+impl Verbosity {
+    pub fn steps(&self) -> bool {
+        self.level >= 1
+    }
+
+    pub fn prompts(&self) -> bool {
+        self.level >= 2
+    }
+
+    pub fn xml(&self) -> bool {
+        self.debug_xml
+    }
+}
+
+// how one changed file fared in file selection
+pub struct SelectionCandidate {
+    pub path: String,
+    pub line_count: usize,
+    pub diff_bytes: usize,
+    // the heuristic's ordering: extension rank (0 preferred, 2 deprioritized), then
+    // src/ first, then the biggest diff
+    pub extension_rank: u8,
+    pub in_src: bool,
+    pub outcome: SelectionOutcome,
+}
+
+pub enum SelectionOutcome {
+    Model,
+    Heuristic,
+    Skipped(&'static str),
+}
+
+pub struct Generation {
+    pub message: String,
+    pub raw_response: String,
+    pub exchanges: Vec<Exchange>,
+    pub selection_response: String,
+    pub selected_files: Vec<String>,
+    pub selection: Vec<SelectionCandidate>,
+    // what the commit prompt showed of the changes, kept for rerolling the subject
+    pub changes_text: String,
+}
+
+impl Generation {
+    // a message that didn't come from the model
+    pub fn from_message(message: String) -> Self {
+        Generation {
+            message,
+            raw_response: String::new(),
+            exchanges: Vec::new(),
+            selection_response: String::new(),
+            selected_files: Vec::new(),
+            selection: Vec::new(),
+            changes_text: String::new(),
+        }
+    }
+}
+
+// same forms as Ollama's own keep_alive: "-1" (forever), "0" (unload right away),
+// plain seconds, or a number with an s/m/h suffix
+fn parse_keep_alive(value: &str) -> Result<KeepAlive> {
+    let value = value.trim();
+    let invalid = || anyhow::anyhow!("Invalid model.keep_alive '{}' (expected e.g. \"10m\", \"-1\" or \"0\")", value);
+    let (negative, magnitude) = match value.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, value),
+    };
+    let (number, unit) = match magnitude.char_indices().last().ok_or_else(invalid)? {
+        (i, 's') => (&magnitude[..i], TimeUnit::Seconds),
+        (i, 'm') => (&magnitude[..i], TimeUnit::Minutes),
+        (i, 'h') => (&magnitude[..i], TimeUnit::Hours),
+        _ => (magnitude, TimeUnit::Seconds),
+    };
+    let time: u64 = number.parse().map_err(|_| invalid())?;
+    Ok(match (negative, time) {
+        (true, _) => KeepAlive::Indefinitely,
+        (false, 0) => KeepAlive::UnloadOnCompletion,
+        (false, time) => KeepAlive::Until { time, unit },
+    })
+}
+
+fn client(config: &ModelConfig) -> Ollama {
+    Ollama::new(config.host.clone(), config.port)
+}
+
+fn server_url(config: &ModelConfig) -> String {
+    format!("{}:{}", config.host, config.port)
+}
+
+// the client turns reqwest's errors into plain messages, so a server that isn't
+// running can only be told apart by what the message says
+fn is_unreachable(error: &OllamaError) -> bool {
+    let message = error.to_string().to_lowercase();
+    ["error sending request", "connection refused", "error trying to connect", "dns error"]
+        .iter()
+        .any(|needle| message.contains(needle))
+}
+
+pub async fn list_models(config: &ModelConfig) -> Result<Vec<LocalModel>> {
+    match client(config).list_local_models().await {
+        Ok(models) => Ok(models),
+        Err(source) if is_unreachable(&source) => Err(CommitGenError::OllamaUnreachable { url: server_url(config), source }),
+        Err(source) => Err(anyhow::Error::new(source)
+            .context(format!("Could not list models from the Ollama server at {}", server_url(config)))
+            .into()),
+    }
+}
+
+// "llama3" is what Ollama lists as "llama3:latest"
+fn is_same_model(listed: &str, configured: &str) -> bool {
+    listed == configured || (!configured.contains(':') && listed.strip_suffix(":latest") == Some(configured))
+}
+
+// the client plus everything recorded about the calls made through it; one per run,
+// so the call budget covers regenerations and model fallbacks too
+pub struct Session {
+    ollama: Ollama,
+    verbosity: Verbosity,
+    exchanges: Vec<Exchange>,
+    calls: u32,
+    // the model it was looked up for, and its context window if the server said
+    context_size: Option<(String, Option<u32>)>,
+    // what the server had pulled when first asked; None until then, or if it couldn't say
+    pulled_models: Option<Vec<String>>,
+    server_checked: bool,
+}
+
+// Ollama's context window when neither model.num_ctx nor the model's Modelfile sets one
+pub const DEFAULT_NUM_CTX: u32 = 2048;
+
+impl Session {
+    pub fn new(config: &ModelConfig, verbosity: Verbosity) -> Self {
+        Session {
+            ollama: client(config),
+            verbosity,
+            exchanges: Vec::new(),
+            calls: 0,
+            context_size: None,
+            pulled_models: None,
+            server_checked: false,
+        }
+    }
+
+    // listing the models first turns a server that isn't running, or a model that isn't
+    // pulled, into an error that says so rather than a failed request
+    async fn check_server(&mut self, config: &Config) -> Result<()> {
+        if !self.server_checked {
+            self.server_checked = true;
+            match self.ollama.list_local_models().await {
+                Ok(models) => self.pulled_models = Some(models.into_iter().map(|model| model.name).collect()),
+                Err(source) if is_unreachable(&source) => {
+                    return Err(CommitGenError::OllamaUnreachable { url: server_url(&config.model), source });
+                }
+                // the request itself will report whatever is wrong
+                Err(_) => {}
+            }
+        }
+        let pulled = self.pulled_models.as_ref()
+            .is_none_or(|models| models.iter().any(|name| is_same_model(name, &config.model.name)));
+        if !pulled {
+            return Err(CommitGenError::ModelNotPulled(config.model.name.clone()));
+        }
+        Ok(())
+    }
+
+    // the configured num_ctx, else the model's own (asked once per model), else nothing
+    // when the server can't tell us
+    async fn context_size(&mut self, config: &Config) -> Option<u32> {
+        if config.model.num_ctx.is_some() {
+            return config.model.num_ctx;
+        }
+        if self.context_size.as_ref().is_none_or(|(model, _)| *model != config.model.name) {
+            let size = self.ollama.show_model_info(config.model.name.clone()).await.ok().map(|info| {
+                info.parameters.lines()
+                    .find_map(|line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                        ["num_ctx", value] => value.parse().ok(),
+                        _ => None,
+                    })
+                    .unwrap_or(DEFAULT_NUM_CTX)
+            });
+            self.context_size = Some((config.model.name.clone(), size));
+        }
+        self.context_size.as_ref().and_then(|(_, size)| *size)
+    }
+
+    // every model call goes through here
+    async fn request(
+        &mut self,
+        config: &Config,
+        stage: &'static str,
+        system: &str,
+        prompt: String,
+        options: GenerationOptions,
+        format: Option<FormatType>,
+    ) -> Result<String> {
+        if let Some(max_calls) = config.model.max_model_calls {
+            if self.calls >= max_calls {
+                return Err(CommitGenError::CallBudgetExceeded(max_calls));
+            }
+        }
+        self.check_server(config).await?;
+        self.calls += 1;
+        if self.verbosity.steps() {
+            match config.model.max_model_calls {
+                Some(max_calls) => println!("=== Model call {}/{} ({}) ===", self.calls, max_calls, stage),
+                None => println!("=== Model call {} ({}) ===", self.calls, stage),
+            }
+        }
+
+        // Ollama silently drops the start of a prompt that doesn't fit; ~4 characters a token
+        let estimate = (system.len() + prompt.len()) / 4;
+        if let Some(context_size) = self.context_size(config).await.filter(|&size| estimate > size as usize) {
+            println!("{}", format!(
+                "Warning: the {} prompt is about {} tokens, more than the {} token context of {}; lower formatting.max_diff_lines or selection.max_files, or raise model.num_ctx",
+                stage, estimate, context_size, config.model.name
+            ).yellow());
+        }
+
+        let options = match config.model.num_ctx {
+            Some(num_ctx) => options.num_ctx(num_ctx),
+            None => options,
+        };
+        let mut request = GenerationRequest::new(config.model.name.to_string(), prompt.clone())
+            .system(system.to_string())
+            .options(options);
+        if let Some(format) = format {
+            request = request.format(format);
+        }
+        if let Some(keep_alive) = &config.model.keep_alive {
+            request = request.keep_alive(parse_keep_alive(keep_alive)?);
+        }
+
+        let response = self.ollama.generate(request).await.map_err(|source| if is_unreachable(&source) {
+            CommitGenError::OllamaUnreachable { url: server_url(&config.model), source }
+        } else {
+            CommitGenError::ModelRequest { model: config.model.name.clone(), source }
+        })?;
+
+        self.exchanges.push(Exchange {
+            stage,
+            model: config.model.name.clone(),
+            system: system.to_string(),
+            prompt,
+            response: response.response.clone(),
+            prompt_tokens: response.final_data.as_ref().map(|data| data.prompt_eval_count as u32),
+            response_tokens: response.final_data.as_ref().map(|data| data.eval_count as u32),
+        });
+        Ok(response.response)
+    }
+}
+
+fn open_tag(name: &str) -> String {
+    format!("<{}>", name)
+}
+
+fn close_tag(name: &str) -> String {
+    format!("</{}>", name)
+}
+
+fn format_reminder(tags: &TagsConfig, json: bool) -> String {
+    if json {
+        return "\n\nIMPORTANT: your previous answer could not be parsed. \
+            Respond ONLY with the JSON object, including a non-empty \"message\"."
+            .to_string();
+    }
+    format!(
+        "\n\nIMPORTANT: your previous answer could not be parsed. \
+        Respond ONLY with the requested {} XML, including a non-empty {} element.",
+        open_tag(&tags.commit),
+        open_tag(&tags.message),
+    )
+}
+
+const COMMIT_TYPES: [&str; 7] = ["feat", "fix", "docs", "style", "refactor", "test", "chore"];
+
+// house style for the text after any "type(scope): " prefix
+fn normalize_subject(message: &str, config: &CommitConfig) -> String {
+    let type_re = regex::Regex::new(r"^[a-z]+(\([^)]*\))?!?: ").unwrap();
+    let (prefix, subject) = message.split_at(type_re.find(message).map_or(0, |m| m.end()));
+
+    let subject = match (config.subject_case, subject.chars().next()) {
+        (SubjectCase::Lower, Some(first)) if !starts_with_acronym(subject) => {
+            first.to_lowercase().chain(subject[first.len_utf8()..].chars()).collect()
+        }
+        (SubjectCase::Sentence, Some(first)) => first.to_uppercase().chain(subject[first.len_utf8()..].chars()).collect(),
+        _ => subject.to_string(),
+    };
+    let subject = match subject.strip_suffix('.') {
+        Some(stripped) if config.strip_trailing_period && !stripped.ends_with('.') => stripped.to_string(),
+        _ => subject,
+    };
+    format!("{}{}", prefix, subject)
+}
+
+// puts the formatted ticket in front of the subject, after any emoji and type prefix,
+// unless the subject already mentions it
+pub fn prefix_ticket(message: &str, ticket: &str, format: &str) -> String {
+    let (subject, rest) = message.split_once('\n').map_or((message, None), |(subject, rest)| (subject, Some(rest)));
+    if subject.contains(ticket) {
+        return message.to_string();
+    }
+    let prefix_re = regex::Regex::new(r"^(?:[^\x00-\x7F]+ )?(?:[a-z]+(\([^)]*\))?!?: (?:[^\x00-\x7F]+ )?)?").unwrap();
+    let (prefix, subject) = subject.split_at(prefix_re.find(subject).map_or(0, |m| m.end()));
+    let subject = format!("{}{}{}", prefix, format.replace("{ticket}", ticket), subject);
+    match rest {
+        Some(rest) => format!("{}\n{}", subject, rest),
+        None => subject,
+    }
+}
+
+// commit.max_subject_length for the whole first line, once the ticket and rewrites are in
+pub fn limit_subject(message: &str, max: usize) -> String {
+    match message.split_once('\n') {
+        Some((subject, rest)) => format!("{}\n{}", truncate_text(subject, max, ' '), rest),
+        None => truncate_text(message, max, ' '),
+    }
+}
+
+// rewrites "*", "-", "+" and "•" bullets with one marker, indented by nesting depth;
+// numbered lists and fenced code blocks are left as written
+fn normalize_bullets(body: &str, marker: char, indent_size: usize) -> String {
+    let bullet_re = regex::Regex::new(r"^([ \t]*)[-*+•][ \t]+(.*)$").unwrap();
+    let mut in_fence = false;
+    // indentation widths of the enclosing bullets, outermost first
+    let mut levels: Vec<usize> = Vec::new();
+    let mut lines = Vec::new();
+    for line in body.lines() {
+        let fence = line.trim_start().starts_with("```");
+        in_fence ^= fence;
+        let captures = if in_fence || fence { None } else { bullet_re.captures(line) };
+        match captures {
+            Some(captures) => {
+                let width = captures[1].chars().map(|c| if c == '\t' { 4 } else { 1 }).sum();
+                while levels.last().is_some_and(|&level| level > width) {
+                    levels.pop();
+                }
+                if levels.last() != Some(&width) {
+                    levels.push(width);
+                }
+                let indent = " ".repeat((levels.len() - 1) * indent_size);
+                lines.push(format!("{}{} {}", indent, marker, &captures[2]));
+            }
+            None => {
+                // anything back at the margin ends the list
+                if !line.starts_with([' ', '\t']) {
+                    levels.clear();
+                }
+                lines.push(line.to_string());
+            }
+        }
+    }
+    lines.join("\n")
+}
+
+// keyword guess at the type of a message that doesn't state one
+fn infer_commit_type(message: &str) -> &'static str {
+    let message_lower = message.to_lowercase();
+    if message_lower.contains("fix") || message_lower.contains("bug") {
+        "fix"
+    } else if message_lower.contains("add") || message_lower.contains("new") || message_lower.contains("feat") {
+        "feat"
+    } else if message_lower.contains("doc") {
+        "docs"
+    } else if message_lower.contains("style") {
+        "style"
+    } else if message_lower.contains("refactor") {
+        "refactor"
+    } else if message_lower.contains("test") {
+        "test"
+    } else {
+        "chore"
+    }
+}
+
+// overrides whatever output format commit_context describes
+const JSON_FORMAT: &str = "\n\nIgnore any XML format described in the request. Respond ONLY with a JSON object \
+of the form {\"type\": \"feat|fix|docs|style|refactor|test|chore\", \"message\": \"single-line summary\", \
+\"description\": [\"bullet point\", ...]}.";
+
+#[derive(Deserialize)]
+struct JsonCommit {
+    message: String,
+    #[serde(default)]
+    description: serde_json::Value,
+    #[serde(default, rename = "type")]
+    commit_type: Option<String>,
+}
+
+impl JsonCommit {
+    // models return the description as either a string or a list of bullets
+    fn description_text(&self) -> String {
+        match &self.description {
+            serde_json::Value::String(text) => text.trim().to_string(),
+            serde_json::Value::Array(items) => items.iter()
+                .filter_map(|item| item.as_str())
+                .map(|item| format!("- {}", item.trim().trim_start_matches("- ")))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => String::new(),
+        }
+    }
+}
+
+fn parse_json_commit(response: &str) -> Option<JsonCommit> {
+    serde_json::from_str(response).ok()
+}
+
+// true when the response has a complete, non-empty message element
+fn has_message(response: &str, tags: &TagsConfig) -> bool {
+    let (open, close) = (open_tag(&tags.message), close_tag(&tags.message));
+    match (response.find(&open), response.find(&close)) {
+        (Some(start), Some(end)) if start + open.len() <= end => !response[start + open.len()..end].trim().is_empty(),
+        _ => false,
+    }
+}
+
+// largest per-file line count such that the capped files together fit the budget
+fn line_cap(mut line_counts: Vec<usize>, budget: usize) -> usize {
+    line_counts.sort_unstable();
+    let mut remaining = budget;
+    let mut files_left = line_counts.len();
+    for count in line_counts {
+        if count * files_left > remaining {
+            return remaining / files_left;
+        }
+        remaining -= count;
+        files_left -= 1;
+    }
+    usize::MAX
+}
+
+fn truncate_diff(diff: &str, head: usize, tail: usize) -> String {
+    let lines: Vec<_> = diff.lines().collect();
+    if head + tail >= lines.len() {
+        return diff.to_string();
+    }
+    format!("{}\n[...{} lines skipped...]\n{}\n",
+        lines[..head].join("\n"),
+        lines.len() - head - tail,
+        lines[lines.len() - tail..].join("\n")
+    )
+}
+
+// text between <tag> and </tag>
+fn tag_content<'a>(text: &'a str, tag: &str) -> Option<&'a str> {
+    let (open, close) = (open_tag(tag), close_tag(tag));
+    let start = text.find(&open)? + open.len();
+    let end = start + text[start..].find(&close)?;
+    Some(text[start..end].trim())
+}
+
+// the answer to a request that stopped at </tag>: the stop sequence eats the closing tag,
+// so only the opening one can be present
+fn after_open_tag<'a>(response: &'a str, tag: &str) -> &'a str {
+    let open = open_tag(tag);
+    match response.find(&open) {
+        Some(start) => response[start + open.len()..].trim(),
+        None => response.trim(),
+    }
+}
+
+// a lockfile's diff is mostly hashes and URLs, so the model only gets the counts of
+// dependency versions that changed. a version line both added and removed is an update
+fn lockfile_summary(path: &str, diff: &str, formatting: &FormattingConfig) -> Option<String> {
+    if !formatting.summarize_lockfiles || !git::is_excluded(path, &formatting.lockfile_patterns) {
+        return None;
+    }
+
+    // version keys of Cargo.lock/poetry.lock, package-lock.json/composer.lock, yarn.lock and
+    // pnpm-lock.yaml, then a go.sum module line and a Gemfile.lock spec
+    let version_re = regex::Regex::new(
+        r#"^\s*("version"\s*:|version\s*=|version\s+"|version:)|^\S+ v[^/\s]+ h1:|^    [^\s(]+ \("#
+    ).unwrap();
+    let (mut added, mut removed, mut insertions, mut deletions) = (0, 0, 0, 0);
+    for line in git::skip_file_header(diff) {
+        if let Some(content) = line.strip_prefix('+') {
+            insertions += 1;
+            added += usize::from(version_re.is_match(content));
+        } else if let Some(content) = line.strip_prefix('-') {
+            deletions += 1;
+            removed += usize::from(version_re.is_match(content));
+        }
+    }
+    let updated = added.min(removed);
+    Some(format!(
+        "Lockfile: updated {} dependencies (+{} -{}); {} lines added, {} removed, raw diff omitted\n",
+        updated, added - updated, removed - updated, insertions, deletions
+    ))
+}
+
+// drops added/removed lines that are nothing but a comment, for extensions with known
+// comment prefixes. only the text sent to the model changes, so hunk counts may be off
+fn strip_comments(path: &str, diff: &str, formatting: &FormattingConfig) -> String {
+    let prefixes = match path.rsplit_once('.') {
+        Some((_, extension)) if formatting.strip_comments => formatting.comment_prefixes.get(extension),
+        _ => None,
+    };
+    let Some(prefixes) = prefixes else {
+        return diff.to_string();
+    };
+
+    let mut stripped = String::new();
+    // the "--- a/path" and "+++ b/path" file header lines come before the first hunk
+    let mut in_hunks = false;
+    for line in diff.lines() {
+        in_hunks = in_hunks || line.starts_with("@@");
+        let is_comment = match line.strip_prefix('+').or_else(|| line.strip_prefix('-')) {
+            Some(content) if in_hunks => {
+                let content = content.trim_start();
+                prefixes.iter().any(|prefix| content.starts_with(prefix.as_str()))
+            }
+            _ => false,
+        };
+        if !is_comment {
+            stripped.push_str(line);
+            stripped.push('\n');
+        }
+    }
+    stripped
+}
+
+// line limits don't help with minified files, where a single line can be hundreds of KB
+fn cap_bytes(diff: &str, max_bytes: Option<usize>) -> String {
+    let Some(max_bytes) = max_bytes.filter(|max_bytes| diff.len() > *max_bytes) else {
+        return diff.to_string();
+    };
+    let mut end = max_bytes;
+    while !diff.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}\n[...{} bytes truncated...]\n", &diff[..end], diff.len() - end)
+}
 
 fn format_prompt(template: &str, replacements: &[(&str, &str)]) -> String {
     let mut result = template.to_string();
@@ -22,15 +579,109 @@ fn format_prompt(template: &str, replacements: &[(&str, &str)]) -> String {
     result
 }
 
-async fn get_files_to_examine(ollama: &Ollama, config: &Config, changes: &GitChanges, verbose: bool) -> Result<HashSet<String>> {
+// 0 = preferred extension, 1 = neutral, 2 = deprioritized
+fn extension_rank(path: &str, selection: &FileSelectionConfig) -> u8 {
+    // checked first so "min.js" can beat a preferred "js"
+    if git::has_extension(path, &selection.deprioritize_extensions) {
+        2
+    } else if git::has_extension(path, &selection.priority_extensions) {
+        0
+    } else {
+        1
+    }
+}
+
+// the response wrapped in the files tags if the model left them out, and whether it is
+// untagged: with strict_xml, prose without the tag says nothing about files, so they are
+// picked by heuristics instead of combing it for file entries
+fn normalize_selection_response(response: &str, strict_xml: bool, tags: &TagsConfig) -> (String, bool) {
+    let mut response_text = response.trim().to_string();
+    let (files_open, files_close) = (open_tag(&tags.files), close_tag(&tags.files));
+
+    let untagged = strict_xml && !response_text.contains(&files_open);
+    if !untagged && !response_text.starts_with(&files_open) {
+        response_text = format!("{}\n{}", files_open, response_text);
+    }
+    // the closing tag is the stop sequence, so ollama never returns it
+    if !response_text.ends_with(&files_close) {
+        response_text.push('\n');
+        response_text.push_str(&files_close);
+    }
+    (response_text, untagged)
+}
+
+// the changed files the response names, one file tag per line
+fn parse_selected_files(response_text: &str, tags: &TagsConfig, changes: &GitChanges) -> BTreeSet<String> {
+    let mut files = BTreeSet::new();
+    let (files_open, files_close) = (open_tag(&tags.files), close_tag(&tags.files));
+
+    if let Some(start) = response_text.find(&files_open) {
+        if let Some(end) = response_text.find(&files_close) {
+            let files_content = &response_text[start + files_open.len()..end];
+            let (file_open, file_close) = (open_tag(&tags.file), close_tag(&tags.file));
+            for line in files_content.lines() {
+                let trimmed = line.trim();
+                if let Some(file_path) = trimmed
+                    .strip_prefix(file_open.as_str())
+                    .and_then(|s| s.strip_suffix(file_close.as_str()))
+                {
+                    // a path the model made up can't be shown, so it shouldn't count toward min_files
+                    let file_path = file_path.trim();
+                    if changes.files.contains_key(file_path) {
+                        files.insert(file_path.to_string());
+                    }
+                }
+            }
+        }
+    }
+    files
+}
+
+// tops the selection up to `wanted` files: preferred extensions, then src/, then the
+// biggest diffs
+fn fill_selection(files: &mut BTreeSet<String>, wanted: usize, config: &Config, changes: &GitChanges) {
+    if files.len() >= wanted {
+        return;
+    }
+    let include_extensions = &config.selection.include_extensions;
+    let mut available_files: Vec<_> = changes.files
+        .iter()
+        // only a file with a diff has anything to show
+        .filter(|(path, change)| !files.contains(*path) && !change.diff.is_empty())
+        .filter(|(path, change)| {
+            change.line_count >= config.selection.min_changes &&
+            !(config.selection.exclude_tests && path.contains("test")) &&
+            !git::is_excluded(path, &config.git.exclude_patterns) &&
+            (include_extensions.is_empty() || git::has_extension(path, include_extensions))
+        })
+        .collect();
+
+    available_files.sort_by_key(|(path, change)| (
+        extension_rank(path, &config.selection),
+        config.selection.prioritize_src && !path.starts_with("src/"),
+        std::cmp::Reverse(change.diff.len()),
+    ));
+
+    for (path, _) in available_files.iter().take(wanted - files.len()) {
+        files.insert((*path).clone());
+    }
+}
+
+async fn get_files_to_examine(
+    session: &mut Session,
+    config: &Config,
+    changes: &GitChanges,
+    verbosity: Verbosity,
+) -> Result<(BTreeSet<String>, String, Vec<SelectionCandidate>)> {
     let indent = " ".repeat(config.formatting.indent_size);
+    let tags = &config.prompts.tags;
     
     let mut changes_summary = changes.summary.clone();
     changes_summary.push_str("\nDetailed file statistics:\n");
     for (path, change) in &changes.files {
         let mut total_changes = 0;
         
-        for line in change.diff.lines() {
+        for line in git::skip_file_header(&change.diff) {
             match line.chars().next() {
                 Some('+') => total_changes += 1,
                 Some('-') => total_changes += 1,
@@ -47,9 +698,11 @@ async fn get_files_to_examine(ollama: &Ollama, config: &Config, changes: &GitCha
     let indent_size = config.formatting.indent_size.to_string();
     let min_files = config.selection.min_files.to_string();
     let max_files = config.selection.max_files.to_string();
+    let repo_name = git::repo_name().unwrap_or_default();
     
     let replacements = [
         (config.prompts.placeholders.changes_summary.as_str(), changes_summary),
+        (config.prompts.placeholders.repo_name.as_str(), repo_name.as_str()),
         (config.prompts.placeholders.indent_size.as_str(), indent_size.as_str()),
         (config.prompts.placeholders.min_files.as_str(), min_files.as_str()),
         (config.prompts.placeholders.max_files.as_str(), max_files.as_str()),
@@ -58,7 +711,7 @@ async fn get_files_to_examine(ollama: &Ollama, config: &Config, changes: &GitCha
     
     let context = format_prompt(&config.prompts.file_selection_context, &replacements);
 
-    if verbose {
+    if verbosity.prompts() {
         println!("\n=== Debug: File selection context ===\n{}\n===\n", context);
     }
 
@@ -66,75 +719,32 @@ async fn get_files_to_examine(ollama: &Ollama, config: &Config, changes: &GitCha
         .temperature(config.model.file_selection_temperature)
         .top_p(config.model.top_p)
         .num_predict(config.model.max_tokens as i32)
-        .stop(vec!["</files>".to_string()]);
+        .stop(vec![close_tag(&tags.files)]);
 
-    let request = GenerationRequest::new(
-        config.model.name.to_string(),
-        context,
-    )
-    .system(config.prompts.file_selection_system.clone())
-    .options(options);
-
-    let response = ollama
-        .generate(request)
+    let response = session.request(config, "file_selection", &config.prompts.file_selection_system, context, options, None)
         .await
         .context("Failed to get file selection")?;
 
-    let mut response_text = response.response.trim().to_string();
-    
-    if !response_text.starts_with("<files>") {
-        response_text = format!("<files>\n{}", response_text);
+    let (response_text, untagged) = normalize_selection_response(&response, config.model.strict_xml, tags);
+    if untagged {
+        println!("{}", format!(
+            "Warning: file selection response has no {} tag, selecting files by size and extension",
+            open_tag(&tags.files)
+        ).yellow());
     }
-    if !response_text.ends_with("</files>") {
-        response_text.push_str("\n</files>");
-    }
-
-    if verbose {
+    if verbosity.xml() {
         println!("=== Debug: Raw LLM Response ===\n{}\n===\n", response_text);
     }
 
-    let mut files = HashSet::new();
+    let include_extensions = &config.selection.include_extensions;
+    let mut files = parse_selected_files(&response_text, tags, changes);
+    files.retain(|path| include_extensions.is_empty() || git::has_extension(path, include_extensions));
 
-    if let Some(start) = response_text.find("<files>") {
-        if let Some(end) = response_text.find("</files>") {
-            let files_content = &response_text[start + 7..end];
-            for line in files_content.lines() {
-                let trimmed = line.trim();
-                if let Some(file_path) = trimmed
-                    .strip_prefix("<file>")
-                    .and_then(|s| s.strip_suffix("</file>"))
-                {
-                    files.insert(file_path.trim().to_string());
-                }
-            }
-        }
-    }
+    let from_model = files.clone();
+    let wanted = if untagged { config.selection.max_files } else { config.selection.min_files };
+    fill_selection(&mut files, wanted, config, changes);
 
-    if files.len() < config.selection.min_files {
-        let mut available_files: Vec<_> = changes.files
-            .iter()
-            .filter(|(path, change)| {
-                !files.contains(*path) && 
-                change.diff.is_empty() && 
-                change.line_count >= config.selection.min_changes &&
-                !(config.selection.exclude_tests && path.contains("test")) &&
-                !config.git.exclude_patterns.iter().any(|pattern| path.contains(pattern))
-            })
-            .collect();
-        
-        if config.selection.prioritize_src {
-            available_files.sort_by_key(|(path, _)| !path.starts_with("src/"));
-        }
-        
-        available_files.sort_by_key(|(_, change)| change.diff.len());
-        available_files.reverse();
-        
-        for (path, _) in available_files.iter().take(config.selection.min_files - files.len()) {
-            files.insert((*path).clone());
-        }
-    }
-
-    if verbose {
+    if verbosity.steps() {
         println!("=== Debug: Selected files for detailed examination ===");
         for file in &files {
             println!("  - {}", file);
@@ -142,63 +752,168 @@ async fn get_files_to_examine(ollama: &Ollama, config: &Config, changes: &GitCha
         println!("===\n");
     }
 
-    Ok(files)
+    let in_src = |path: &str| config.selection.prioritize_src && path.starts_with("src/");
+    let selection = changes.files.iter().map(|(path, change)| {
+        let outcome = if from_model.contains(path) {
+            SelectionOutcome::Model
+        } else if files.contains(path) {
+            SelectionOutcome::Heuristic
+        } else {
+            // the first of the heuristic's filters the file failed
+            SelectionOutcome::Skipped(if change.diff.is_empty() {
+                "no diff"
+            } else if change.line_count < config.selection.min_changes {
+                "under selection.min_changes"
+            } else if config.selection.exclude_tests && path.contains("test") {
+                "test file (selection.exclude_tests)"
+            } else if git::is_excluded(path, &config.git.exclude_patterns) {
+                "git.exclude_patterns"
+            } else if !include_extensions.is_empty() && !git::has_extension(path, include_extensions) {
+                "not in selection.include_extensions"
+            } else if from_model.len() < wanted {
+                "ranked below the files taken"
+            } else {
+                "not chosen by the model"
+            })
+        };
+        SelectionCandidate {
+            path: path.clone(),
+            line_count: change.line_count,
+            diff_bytes: change.diff.len(),
+            extension_rank: extension_rank(path, &config.selection),
+            in_src: in_src(path),
+            outcome,
+        }
+    }).collect();
+
+    Ok((files, response_text, selection))
 }
 
-pub async fn generate_commit_message(config: &Config, changes: &GitChanges, verbose: bool) -> Result<(String, String)> {
-    let ollama = Ollama::default();
-    
-    let files_to_examine = get_files_to_examine(&ollama, config, changes, verbose).await?;
-    
+// errors that another model might not hit: missing model, out of memory, load failures.
+// connection problems and timeouts would fail the same way for every model.
+fn is_model_error(error: &CommitGenError) -> bool {
+    let mut chain = std::iter::successors(Some(error as &dyn std::error::Error), |cause| cause.source());
+    chain.any(|cause| {
+        let message = cause.to_string().to_lowercase();
+        message.contains("model")
+            && ["not found", "memory", "failed to load", "pull"].iter().any(|needle| message.contains(needle))
+    })
+}
+
+// tries model.name, then each of model.fallback_models in order; the model that worked
+// is left in config.model.name so later calls (regeneration, logging) keep using it
+pub async fn generate_with_fallback(session: &mut Session, config: &mut Config, changes: &GitChanges, verbosity: Verbosity) -> Result<Generation> {
+    loop {
+        match generate_commit_message(session, config, changes, verbosity).await {
+            Err(e) if is_model_error(&e) && !config.model.fallback_models.is_empty() => {
+                let next = config.model.fallback_models.remove(0);
+                println!("{} {:#}", format!("Warning: model {} failed, falling back to {}:", config.model.name, next).yellow(), e);
+                config.model.name = next;
+            }
+            result => {
+                if result.is_ok() && verbosity.steps() {
+                    println!("=== Message generated by {} ===", config.model.name);
+                }
+                return result;
+            }
+        }
+    }
+}
+
+// the diffs of the selected files, followed by a short excerpt of every other file
+fn format_changes(config: &Config, changes: &GitChanges, files_to_examine: &BTreeSet<String>) -> String {
     let mut changes_text = String::new();
     
+    // with a total budget, the biggest diffs give up lines first so small ones stay whole
+    let line_cap = config.formatting.total_diff_lines.map(|total| {
+        let selected_counts = changes.files
+            .iter()
+            .filter(|(path, change)| files_to_examine.contains(*path) && !change.diff.is_empty())
+            .map(|(_, change)| change.line_count)
+            .collect();
+        line_cap(selected_counts, total)
+    });
+
+    // committed changes (--since) are neither staged nor unstaged
+    let label_staged = config.formatting.label_staged && changes.committed.is_empty();
+    let status = |change: &FileChange| match (label_staged, change.staged) {
+        (true, true) => format!("{}, staged", change.status),
+        (true, false) => format!("{}, unstaged", change.status),
+        (false, _) => change.status.clone(),
+    };
+    let sections = &config.prompts.sections;
+    let file_header = |path: &str, change: &FileChange| {
+        let template = if config.formatting.show_file_stats { &sections.file_with_stats } else { &sections.file };
+        format_prompt(template, &[
+            ("{path}", path),
+            ("{status}", &status(change)),
+            ("{lines}", &change.line_count.to_string()),
+        ])
+    };
+
     let mut has_diffs = false;
     for (path, change) in &changes.files {
         if files_to_examine.contains(path) && !change.diff.is_empty() {
             if !has_diffs {
-                changes_text.push_str("Detailed changes in selected files:\n");
+                changes_text.push_str(&format!("{}\n", sections.detailed));
                 has_diffs = true;
             }
-            if config.formatting.show_file_stats {
-                changes_text.push_str(&format!("\nIn {} ({}) - {} lines changed:\n```diff\n", path, change.status, change.line_count));
-            } else {
-                changes_text.push_str(&format!("\nIn {} ({}):\n```diff\n", path, change.status));
+            changes_text.push_str(&format!("\n{}\n```diff\n", file_header(path, change)));
+            if let Some(summary) = lockfile_summary(path, &change.diff, &config.formatting) {
+                changes_text.push_str(&summary);
+                changes_text.push_str("```\n");
+                continue;
             }
             
-            if change.line_count > config.formatting.max_diff_lines {
-                let lines: Vec<_> = change.diff.lines().collect();
-                let first_lines = lines.iter().take(config.formatting.preview_lines).cloned().collect::<Vec<_>>().join("\n");
-                let last_lines = lines.iter().rev().take(config.formatting.summary_lines).cloned().collect::<Vec<_>>().join("\n");
-                changes_text.push_str(&format!("{}\n[...{} lines skipped...]\n{}\n", 
-                    first_lines, 
-                    change.line_count - config.formatting.preview_lines - config.formatting.summary_lines,
-                    last_lines
-                ));
-            } else {
-                changes_text.push_str(&change.diff);
-            }
+            let diff = strip_comments(path, &change.diff, &config.formatting);
+            let diff = match line_cap {
+                Some(cap) if change.line_count > cap => {
+                    let head = cap - cap / 3;
+                    truncate_diff(&diff, head, cap - head)
+                }
+                None if change.line_count > config.formatting.max_diff_lines => {
+                    truncate_diff(
+                        &diff,
+                        config.formatting.preview_lines,
+                        config.formatting.summary_lines,
+                    )
+                }
+                _ => diff,
+            };
+            changes_text.push_str(&cap_bytes(&diff, config.formatting.max_diff_bytes));
             changes_text.push_str("```\n");
         }
     }
     
-    let mut other_changes = false;
+    let mut other_changes = 0;
+    let mut omitted = 0;
     for (path, change) in &changes.files {
         if !files_to_examine.contains(path) && !change.diff.is_empty() {
-            if !other_changes {
-                changes_text.push_str("\nOther changes (summarized):\n");
-                other_changes = true;
+            if config.formatting.max_other_files.is_some_and(|max| other_changes >= max) {
+                omitted += 1;
+                continue;
             }
-            if config.formatting.show_file_stats {
-                changes_text.push_str(&format!("\nIn {} ({}) - {} lines changed:\n```diff\n", path, change.status, change.line_count));
-            } else {
-                changes_text.push_str(&format!("\nIn {} ({}):\n```diff\n", path, change.status));
+            if other_changes == 0 {
+                changes_text.push_str(&format!("\n{}\n", sections.other));
             }
+            other_changes += 1;
+            changes_text.push_str(&format!("\n{}\n```diff\n", file_header(path, change)));
             
-            let first_lines = change.diff.lines().take(config.formatting.summary_lines).collect::<Vec<_>>().join("\n");
-            if change.line_count > config.formatting.summary_lines {
+            if let Some(summary) = lockfile_summary(path, &change.diff, &config.formatting) {
+                changes_text.push_str(&summary);
+                changes_text.push_str("```\n");
+                continue;
+            }
+
+            // the file header would eat the whole summary, so start at the first hunk
+            let diff = strip_comments(path, &change.diff, &config.formatting);
+            let hunks: Vec<_> = git::skip_file_header(&diff).collect();
+            let first_lines = hunks.iter().take(config.formatting.summary_lines).copied().collect::<Vec<_>>().join("\n");
+            let first_lines = cap_bytes(&first_lines, config.formatting.max_diff_bytes);
+            if hunks.len() > config.formatting.summary_lines {
                 changes_text.push_str(&format!("{}\n[...{} additional lines not shown...]\n", 
                     first_lines, 
-                    change.line_count - config.formatting.summary_lines
+                    hunks.len() - config.formatting.summary_lines
                 ));
             } else {
                 changes_text.push_str(&first_lines);
@@ -206,117 +921,170 @@ pub async fn generate_commit_message(config: &Config, changes: &GitChanges, verb
             changes_text.push_str("```\n");
         }
     }
-
-    let indent = " ".repeat(config.formatting.indent_size);
-    let replacements = [
-        (config.prompts.placeholders.changes_summary.as_str(), changes.summary.as_str()),
-        (config.prompts.placeholders.changes_text.as_str(), &changes_text),
-        (config.prompts.placeholders.indent_size.as_str(), &config.formatting.indent_size.to_string()),
-        (config.prompts.placeholders.max_message_length.as_str(), &config.commit.max_message_length.to_string()),
-        ("indent", &indent),
-    ];
-    
-    let context = format_prompt(&config.prompts.commit_context, &replacements);
-    
-    if verbose {
-        println!("\n=== Debug: Context sent to LLM ===\n{}\n===\n", context);
+    if omitted > 0 {
+        changes_text.push_str(&format!("\n{}\n", sections.omitted.replace("{count}", &omitted.to_string())));
     }
 
-    let options = GenerationOptions::default()
-        .temperature(config.model.commit_temperature)
-        .top_p(config.model.top_p)
-        .num_predict(config.model.max_tokens as i32)
-        .stop(vec!["</commit>".to_string()]);
+    changes_text
+}
 
-    let request = GenerationRequest::new(
-        config.model.name.to_string(),
-        context,
-    )
-    .system(config.prompts.commit_system.clone())
-    .options(options);
-    
-    let response = ollama
-        .generate(request)
-        .await
-        .context("Failed to generate commit message")?;
-    
-    let mut commit_message = response.response.trim().to_string();
-    
-    if !commit_message.starts_with("<commit>") {
-        commit_message = format!("<commit>\n{}", commit_message);
+// for huge change sets: one line per file instead of any diff
+fn format_stat_only(changes: &GitChanges) -> String {
+    let mut text = format!("Per-file statistics for {} files (diffs omitted, describe the change at a high level):\n", changes.files.len());
+    let (mut total_insertions, mut total_deletions) = (0, 0);
+    for (path, change) in &changes.files {
+        let lines: Vec<_> = git::skip_file_header(&change.diff).collect();
+        let insertions = lines.iter().filter(|line| line.starts_with('+')).count();
+        let deletions = lines.iter().filter(|line| line.starts_with('-')).count();
+        total_insertions += insertions;
+        total_deletions += deletions;
+        text.push_str(&format!("  {} ({}) | +{} -{}\n", path, change.status, insertions, deletions));
     }
-    if !commit_message.ends_with("</commit>") {
-        commit_message.push_str("\n</commit>");
+    text.push_str(&format!("Total: +{} -{}\n", total_insertions, total_deletions));
+    text
+}
+
+// what the prompts get to see of the changes: the selected diffs (or just statistics
+// for huge change sets) and the touched symbols
+struct DescribedChanges {
+    files_to_examine: BTreeSet<String>,
+    selection_response: String,
+    selection: Vec<SelectionCandidate>,
+    changes_text: String,
+    changed_symbols: String,
+}
+
+// a prompt about a set of changes: every such template can use the same placeholders,
+// on top of the caller's own (subject, body, draft, ...) in `extra`
+struct ChangePrompt<'a> {
+    stage: &'static str,
+    // what -v prints while the request runs, e.g. "Reviewing commit message"
+    doing: &'a str,
+    system: &'a str,
+    template: &'a str,
+    changes: &'a GitChanges,
+    changes_text: &'a str,
+    changed_symbols: &'a str,
+    extra: Vec<(&'a str, &'a str)>,
+    stop: Option<String>,
+}
+
+impl ChangePrompt<'_> {
+    fn context(&self, config: &Config) -> String {
+        let placeholders = &config.prompts.placeholders;
+        let indent = " ".repeat(config.formatting.indent_size);
+        let indent_size = config.formatting.indent_size.to_string();
+        let max_message_length = config.commit.max_message_length.to_string();
+        let repo_name = git::repo_name().unwrap_or_default();
+        let mut replacements = self.extra.clone();
+        replacements.extend([
+            (placeholders.repo_name.as_str(), repo_name.as_str()),
+            (placeholders.changes_summary.as_str(), self.changes.summary.as_str()),
+            (placeholders.changes_text.as_str(), self.changes_text),
+            (placeholders.changed_symbols.as_str(), self.changed_symbols),
+            (placeholders.indent_size.as_str(), indent_size.as_str()),
+            (placeholders.max_message_length.as_str(), max_message_length.as_str()),
+            ("indent", indent.as_str()),
+        ]);
+        format_prompt(self.template, &replacements)
     }
 
-    // some old edge case cleanup
-    // commit_message = commit_message
-    //     .replace("  message:", "  <message>")
-    //     .replace("  description:", "  <description>")
-    //     .replace("</message\n", "</message>\n")
-    //     .replace("</description\n", "</description>\n");
-    
-    if verbose {
-        println!("=== Debug: Raw LLM Response ===\n{}\n===\n", commit_message);
+    // a plain-text request at the commit temperature
+    async fn send(&self, session: &mut Session, config: &Config, verbosity: Verbosity) -> Result<String> {
+        let context = self.context(config);
+        if verbosity.steps() {
+            println!("=== {} with {} ===", self.doing, config.model.name);
+        }
+        if verbosity.prompts() {
+            println!("\n=== Debug: {} context sent to LLM ===\n{}\n===\n", self.stage, context);
+        }
+
+        let options = GenerationOptions::default()
+            .temperature(config.model.commit_temperature)
+            .top_p(config.model.top_p)
+            .num_predict(config.model.max_tokens as i32);
+        let options = match &self.stop {
+            Some(stop) => options.stop(vec![stop.clone()]),
+            None => options,
+        };
+        session.request(config, self.stage, self.system, context, options, None).await
     }
+}
 
-    let message = if let Some(start) = commit_message.find("<message>") {
-        if let Some(end) = commit_message.find("</message>") {
-            if verbose {
-                println!("=== Debug: Found message tags at positions {} to {} ===\n", start, end);
-            }
-            commit_message[start + 9..end].trim().to_string()
-        } else {
-            if verbose {
-                println!("=== Debug: Found opening <message> but no closing tag ===\n");
-            }
-            commit_message.trim().to_string()
+async fn describe_changes(session: &mut Session, config: &Config, changes: &GitChanges, verbosity: Verbosity) -> Result<DescribedChanges> {
+    let stat_only = config.formatting.stat_only_threshold.is_some_and(|threshold| changes.files.len() > threshold);
+    let (files_to_examine, selection_response, selection, changes_text) = if stat_only {
+        if verbosity.steps() {
+            println!("=== {} files changed, sending statistics only ===", changes.files.len());
         }
+        (BTreeSet::new(), String::new(), Vec::new(), format_stat_only(changes))
     } else {
-        if verbose {
-            println!("=== Debug: No message tags found ===\n");
+        if verbosity.steps() {
+            println!("=== Selecting files to examine with {} ===", config.model.name);
+        }
+        let (mut files, response, mut selection) = get_files_to_examine(session, config, changes, verbosity).await?;
+        // unstaged files are still summarized, just not shown in detail
+        if config.formatting.details_staged_only && changes.files.values().any(|change| change.staged) {
+            files.retain(|path| changes.files.get(path).is_some_and(|change| change.staged));
+            for candidate in selection.iter_mut().filter(|candidate| !files.contains(&candidate.path)) {
+                if !matches!(candidate.outcome, SelectionOutcome::Skipped(_)) {
+                    candidate.outcome = SelectionOutcome::Skipped("unstaged (formatting.details_staged_only)");
+                }
+            }
         }
-        commit_message.trim().to_string()
+        let changes_text = format_changes(config, changes, &files);
+        (files, response, selection, changes_text)
     };
 
-    if verbose {
-        println!("=== Debug: Extracted message ===\n{}\n===\n", message);
+    let changed_symbols = list_changed_symbols(changes);
+    Ok(DescribedChanges { files_to_examine, selection_response, selection, changes_text, changed_symbols })
+}
+
+fn list_changed_symbols(changes: &GitChanges) -> String {
+    let mut changed_symbols = String::new();
+    for (path, change) in &changes.files {
+        if !change.changed_symbols.is_empty() {
+            changed_symbols.push_str(&format!("{}: {}\n", path, change.changed_symbols.join(", ")));
+        }
     }
+    changed_symbols
+}
+
+// the type prefix, house-style subject and emoji, applied to the subject the model wrote
+fn style_subject(message: String, suggested_type: Option<&str>, config: &CommitConfig, verbosity: Verbosity) -> String {
     let mut final_message = message;
     
-    if config.commit.conventional {
-        if !final_message.contains("feat:") 
-            && !final_message.contains("fix:") 
-            && !final_message.contains("docs:") 
-            && !final_message.contains("style:") 
-            && !final_message.contains("refactor:") 
-            && !final_message.contains("test:") 
-            && !final_message.contains("chore:") {
-            let message_lower = final_message.to_lowercase();
-            let commit_type = if message_lower.contains("fix") || message_lower.contains("bug") {
-                "fix"
-            } else if message_lower.contains("add") || message_lower.contains("new") || message_lower.contains("feat") {
-                "feat"
-            } else if message_lower.contains("doc") {
-                "docs"
-            } else if message_lower.contains("style") {
-                "style"
-            } else if message_lower.contains("refactor") {
-                "refactor"
-            } else if message_lower.contains("test") {
-                "test"
-            } else {
-                "chore"
-            };
-            final_message = format!("{}: {}", commit_type, final_message);
-            if verbose {
-                println!("=== Debug: Added conventional commit type ===\n{}\n===\n", final_message);
-            }
+    if config.conventional
+        && !final_message.contains("feat:") 
+        && !final_message.contains("fix:") 
+        && !final_message.contains("docs:") 
+        && !final_message.contains("style:") 
+        && !final_message.contains("refactor:") 
+        && !final_message.contains("test:") 
+        && !final_message.contains("chore:") {
+        // a JSON response can name the type itself
+        let commit_type = suggested_type
+            .filter(|t| COMMIT_TYPES.contains(t))
+            .unwrap_or_else(|| infer_commit_type(&final_message));
+        final_message = format!("{}: {}", commit_type, final_message);
+        if verbosity.steps() {
+            println!("=== Debug: Added conventional commit type ===\n{}\n===\n", final_message);
         }
     }
-    
-    if config.commit.emoji {
-        let emoji = match final_message.split(':').next().unwrap_or("") {
+
+    final_message = normalize_subject(&final_message, config);
+
+    if config.emoji && config.emoji_position != EmojiPosition::None {
+        // with conventional off there is no type prefix, so infer one just to pick the emoji
+        let leading = final_message.split(':').next().unwrap_or("").split(['(', '!']).next().unwrap_or("");
+        let commit_type = if COMMIT_TYPES.contains(&leading) {
+            leading
+        } else {
+            suggested_type
+                .filter(|t| COMMIT_TYPES.contains(t))
+                .unwrap_or_else(|| infer_commit_type(&final_message))
+        };
+        let emoji = match commit_type {
             "feat" => "✨",
             "fix" => "🐛",
             "docs" => "📚",
@@ -326,26 +1094,633 @@ pub async fn generate_commit_message(config: &Config, changes: &GitChanges, verb
             "chore" => "🔨",
             _ => "🔨",
         };
-        final_message = format!("{} {}", emoji, final_message);
-        if verbose {
+        final_message = match (config.emoji_position, final_message.split_once(": ")) {
+            (EmojiPosition::AfterType, Some((commit_type, subject))) => format!("{}: {} {}", commit_type, emoji, subject),
+            _ => format!("{} {}", emoji, final_message),
+        };
+        if verbosity.steps() {
             println!("=== Debug: Added emoji ===\n{}\n===\n", final_message);
         }
     }
 
-    if let Some(start) = commit_message.find("<description>") {
-        if let Some(end) = commit_message.find("</description>") {
-            if verbose {
-                println!("=== Debug: Found description tags at positions {} to {} ===\n", start, end);
+    final_message
+}
+
+// cut to at most `max` characters, back at the last `boundary` if there is one, so a
+// word (or line) isn't split
+fn truncate_text(text: &str, max: usize, boundary: char) -> String {
+    let Some((cut, _)) = text.char_indices().nth(max) else {
+        return text.to_string();
+    };
+    let kept = &text[..cut];
+    let kept = kept.rfind(boundary).map_or(kept, |end| &kept[..end]);
+    kept.trim_end_matches([' ', ',', ';', '-']).to_string()
+}
+
+pub async fn generate_commit_message(session: &mut Session, config: &Config, changes: &GitChanges, verbosity: Verbosity) -> Result<Generation> {
+    
+    let DescribedChanges { files_to_examine, selection_response, selection, changes_text, changed_symbols } =
+        describe_changes(session, config, changes, verbosity).await?;
+
+    let prompt = ChangePrompt {
+        stage: "commit",
+        doing: "Generating commit message",
+        system: &config.prompts.commit_system,
+        template: &config.prompts.commit_context,
+        changes,
+        changes_text: &changes_text,
+        changed_symbols: &changed_symbols,
+        extra: Vec::new(),
+        stop: None,
+    };
+    let context = prompt.context(config);
+    
+    if verbosity.prompts() {
+        println!("\n=== Debug: Context sent to LLM ===\n{}\n===\n", context);
+    }
+
+    if verbosity.steps() {
+        println!("=== Generating commit message with {} ===", config.model.name);
+    }
+
+    let tags = &config.prompts.tags;
+    let (commit_open, commit_close) = (open_tag(&tags.commit), close_tag(&tags.commit));
+    let (message_open, message_close) = (open_tag(&tags.message), close_tag(&tags.message));
+    let (description_open, description_close) = (open_tag(&tags.description), close_tag(&tags.description));
+
+    let json = config.model.response_format == ResponseFormat::Json;
+    let expected = if json { "JSON \"message\"".to_string() } else { message_open.clone() };
+
+    let mut system = config.prompts.commit_system.clone();
+    if json {
+        system.push_str(JSON_FORMAT);
+    }
+    let mut attempt = 0;
+    let mut commit_message = loop {
+        // each retry halves the temperature, so it isn't just the same bad answer again
+        let floor = config.model.retry_temperature_floor.min(config.model.commit_temperature);
+        let temperature = (config.model.commit_temperature * 0.5f32.powi(attempt as i32)).max(floor);
+        let options = GenerationOptions::default()
+            .temperature(temperature)
+            .top_p(config.model.top_p)
+            .num_predict(config.model.max_tokens as i32);
+        // a JSON object has no closing tag to stop at
+        let options = if json { options } else { options.stop(vec![commit_close.clone()]) };
+
+        let response = session.request(config, "commit", &system, context.clone(), options, json.then_some(FormatType::Json))
+            .await
+            .context("Failed to generate commit message")?;
+        let response = response.trim().to_string();
+
+        let parseable = if json {
+            parse_json_commit(&response).is_some_and(|commit| !commit.message.trim().is_empty())
+        } else {
+            has_message(&response, tags)
+        };
+        if parseable {
+            break response;
+        }
+        if attempt >= config.model.max_format_retries {
+            if response.is_empty() {
+                return Err(CommitGenError::ModelEmptyResponse(config.model.name.clone()));
+            }
+            if attempt > 0 {
+                println!("{}", format!(
+                    "Warning: no parseable {} after {} retries, using best-effort extraction",
+                    expected, attempt
+                ).yellow());
+            }
+            break response;
+        }
+
+        attempt += 1;
+        if verbosity.steps() {
+            println!("=== Debug: No parseable {}, retrying ({}/{}) ===\n{}\n===\n", expected, attempt, config.model.max_format_retries, response);
+        }
+        if attempt == 1 {
+            system.push_str(&format_reminder(tags, json));
+        }
+    };
+
+    let (message, inline_description, suggested_type) = if json {
+        if verbosity.xml() {
+            println!("=== Debug: Raw LLM Response ===\n{}\n===\n", commit_message);
+        }
+        match parse_json_commit(&commit_message) {
+            Some(commit) => (commit.message.trim().to_string(), commit.description_text(), commit.commit_type),
+            None if config.model.strict_xml => {
+                return Err(anyhow::anyhow!(
+                    "Model response is not the requested JSON object (strict_xml is enabled):\n{}",
+                    commit_message
+                ).into());
             }
-            let description = commit_message[start + 13..end].trim();
-            if !description.is_empty() {
-                final_message = format!("{}\n\n{}", final_message, description);
-                if verbose {
-                    println!("=== Debug: Added description ===\n{}\n===\n", final_message);
+            None => (commit_message.trim().to_string(), String::new(), None),
+        }
+    } else {
+        if config.model.strict_xml {
+            if !commit_message.contains(&commit_open) || !commit_message.contains(&message_open) {
+                return Err(anyhow::anyhow!(
+                    "Model response is missing the {}/{} tags (strict_xml is enabled):\n{}",
+                    commit_open,
+                    message_open,
+                    commit_message
+                ).into());
+            }
+        } else if !commit_message.starts_with(&commit_open) {
+            commit_message = format!("{}\n{}", commit_open, commit_message);
+        }
+        if !commit_message.ends_with(&commit_close) {
+            commit_message.push('\n');
+            commit_message.push_str(&commit_close);
+        }
+
+        // some old edge case cleanup
+        // commit_message = commit_message
+        //     .replace("  message:", "  <message>")
+        //     .replace("  description:", "  <description>")
+        //     .replace("</message\n", "</message>\n")
+        //     .replace("</description\n", "</description>\n");
+        
+        if verbosity.xml() {
+            println!("=== Debug: Raw LLM Response ===\n{}\n===\n", commit_message);
+        }
+
+        let message = if let Some(start) = commit_message.find(&message_open) {
+            if let Some(end) = commit_message.find(&message_close) {
+                if verbosity.xml() {
+                    println!("=== Debug: Found message tags at positions {} to {} ===\n", start, end);
+                }
+                commit_message[start + message_open.len()..end].trim().to_string()
+            } else {
+                if verbosity.xml() {
+                    println!("=== Debug: Found opening {} but no closing tag ===\n", message_open);
+                }
+                commit_message.trim().to_string()
+            }
+        } else {
+            if verbosity.xml() {
+                println!("=== Debug: No message tags found ===\n");
+            }
+            commit_message.trim().to_string()
+        };
+
+        let description = match (commit_message.find(&description_open), commit_message.find(&description_close)) {
+            (Some(start), Some(end)) => {
+                if verbosity.xml() {
+                    println!("=== Debug: Found description tags at positions {} to {} ===\n", start, end);
                 }
+                commit_message[start + description_open.len()..end].trim().to_string()
             }
+            _ => String::new(),
+        };
+        (message, description, None)
+    };
+
+    let mut final_message = style_subject(message, suggested_type.as_deref(), &config.commit, verbosity);
+
+    // two-phase mode: the body gets its own call, conditioned on the subject chosen above
+    let description = if let Some(template) = &config.prompts.description_context {
+        let description_context = ChangePrompt {
+            template,
+            extra: vec![(config.prompts.placeholders.subject.as_str(), &final_message)],
+            ..prompt
+        }.context(config);
+
+        if verbosity.steps() {
+            println!("=== Generating description with {} ===", config.model.name);
+        }
+        if verbosity.prompts() {
+            println!("\n=== Debug: Description context sent to LLM ===\n{}\n===\n", description_context);
+        }
+
+        let options = GenerationOptions::default()
+            .temperature(config.model.description_temperature.unwrap_or(config.model.commit_temperature))
+            .top_p(config.model.top_p)
+            .num_predict(config.model.max_tokens as i32)
+            .stop(vec![description_close.clone()]);
+        let response = session.request(config, "description", &config.prompts.commit_system, description_context, options, None)
+            .await
+            .context("Failed to generate commit description")?;
+        if verbosity.xml() {
+            println!("=== Debug: Raw description response ===\n{}\n===\n", response);
+        }
+        after_open_tag(&response, &tags.description).to_string()
+    } else {
+        inline_description
+    };
+    let description = match config.commit.bullet_marker {
+        Some(marker) => normalize_bullets(&description, marker, config.formatting.indent_size),
+        None => description,
+    };
+    let description = match config.commit.max_body_length {
+        Some(max) if description.chars().count() > max => format!("{}\n(truncated)", truncate_text(&description, max, '\n')),
+        _ => description,
+    };
+
+    if !description.is_empty() {
+        final_message = format!("{}\n\n{}", final_message, description);
+        if verbosity.steps() {
+            println!("=== Debug: Added description ===\n{}\n===\n", final_message);
         }
     }
     
-    Ok((final_message, commit_message))
-} 
\ No newline at end of file
+    let selected_files: Vec<_> = files_to_examine.into_iter().collect();
+
+    Ok(Generation {
+        message: final_message,
+        raw_response: commit_message,
+        exchanges: std::mem::take(&mut session.exchanges),
+        selection_response,
+        selected_files,
+        selection,
+        changes_text,
+    })
+}
+
+// a new subject line for a message whose body is kept, styled like a generated one
+pub async fn reroll_subject(
+    session: &mut Session,
+    config: &Config,
+    changes: &GitChanges,
+    generation: &Generation,
+    verbosity: Verbosity,
+) -> Result<String> {
+    // a typed or template message never had its changes described
+    let changes_text = if generation.changes_text.is_empty() {
+        describe_changes(session, config, changes, verbosity).await?.changes_text
+    } else {
+        generation.changes_text.clone()
+    };
+    let body = generation.message.split_once("\n\n").map_or("", |(_, body)| body);
+    let changed_symbols = list_changed_symbols(changes);
+
+    let response = ChangePrompt {
+        stage: "subject",
+        doing: "Generating a new subject",
+        system: &config.prompts.commit_system,
+        template: &config.prompts.subject_context,
+        changes,
+        changes_text: &changes_text,
+        changed_symbols: &changed_symbols,
+        extra: vec![(config.prompts.placeholders.body.as_str(), body)],
+        stop: Some(close_tag(&config.prompts.tags.message)),
+    }
+    .send(session, config, verbosity)
+    .await
+    .context("Failed to generate a new subject")?;
+    if verbosity.xml() {
+        println!("=== Debug: Raw subject response ===\n{}\n===\n", response);
+    }
+
+    let subject = after_open_tag(&response, &config.prompts.tags.message).lines().next().unwrap_or("").to_string();
+    Ok(style_subject(subject, None, &config.commit, verbosity))
+}
+
+// the model's critique of the user's draft next to the generated message, as plain text
+pub async fn compare_messages(
+    session: &mut Session,
+    config: &Config,
+    changes: &GitChanges,
+    generation: &Generation,
+    message: &str,
+    draft: &str,
+    verbosity: Verbosity,
+) -> Result<String> {
+    let changes_text = if generation.changes_text.is_empty() {
+        describe_changes(session, config, changes, verbosity).await?.changes_text
+    } else {
+        generation.changes_text.clone()
+    };
+    let changed_symbols = list_changed_symbols(changes);
+
+    let response = ChangePrompt {
+        stage: "compare",
+        doing: "Comparing the draft",
+        system: &config.prompts.compare_system,
+        template: &config.prompts.compare_context,
+        changes,
+        changes_text: &changes_text,
+        changed_symbols: &changed_symbols,
+        extra: vec![
+            (config.prompts.placeholders.draft.as_str(), draft),
+            (config.prompts.placeholders.commit_message.as_str(), message),
+        ],
+        stop: None,
+    }
+    .send(session, config, verbosity)
+    .await
+    .context("Failed to compare the messages")?;
+    Ok(response.trim().to_string())
+}
+
+// advisory feedback on an existing commit's message, as plain text
+pub async fn review_commit(
+    session: &mut Session,
+    config: &Config,
+    changes: &GitChanges,
+    message: &str,
+    verbosity: Verbosity,
+) -> Result<String> {
+    let DescribedChanges { changes_text, changed_symbols, .. } =
+        describe_changes(session, config, changes, verbosity).await?;
+
+    let response = ChangePrompt {
+        stage: "review",
+        doing: "Reviewing commit message",
+        system: &config.prompts.review_system,
+        template: &config.prompts.review_context,
+        changes,
+        changes_text: &changes_text,
+        changed_symbols: &changed_symbols,
+        extra: vec![(config.prompts.placeholders.commit_message.as_str(), message)],
+        stop: None,
+    }
+    .send(session, config, verbosity)
+    .await
+    .context("Failed to review commit message")?;
+    Ok(response.trim().to_string())
+}
+
+// markdown release notes: the model's introduction, then the commits grouped by
+// conventional type in COMMIT_TYPES order, untyped ones last
+pub async fn generate_release_notes(
+    session: &mut Session,
+    config: &Config,
+    changes: &GitChanges,
+    subjects: &[String],
+    tag: &str,
+    verbosity: Verbosity,
+) -> Result<String> {
+    let DescribedChanges { changes_text, changed_symbols, .. } =
+        describe_changes(session, config, changes, verbosity).await?;
+
+    let commit_subjects = subjects.iter().map(|subject| format!("- {}\n", subject)).collect::<String>();
+    let introduction = ChangePrompt {
+        stage: "release_notes",
+        doing: "Generating release notes",
+        system: &config.prompts.release_system,
+        template: &config.prompts.release_context,
+        changes,
+        changes_text: &changes_text,
+        changed_symbols: &changed_symbols,
+        extra: vec![(config.prompts.placeholders.commit_subjects.as_str(), commit_subjects.as_str())],
+        stop: None,
+    }
+    .send(session, config, verbosity)
+    .await
+    .context("Failed to generate release notes")?;
+
+    // an emoji may come before the type, and the description after it
+    let type_re = regex::Regex::new(r"^(?:[^\x00-\x7F]+ )?([a-z]+)(?:\([^)]*\))?!?: (.+)$").unwrap();
+    let mut groups: BTreeMap<usize, Vec<&str>> = BTreeMap::new();
+    for subject in subjects {
+        let (rank, text) = match type_re.captures(subject) {
+            Some(captures) => match COMMIT_TYPES.iter().position(|t| *t == &captures[1]) {
+                Some(rank) => (rank, captures.get(2).map_or(subject.as_str(), |m| m.as_str())),
+                None => (COMMIT_TYPES.len(), subject.as_str()),
+            },
+            None => (COMMIT_TYPES.len(), subject.as_str()),
+        };
+        groups.entry(rank).or_default().push(text);
+    }
+
+    let mut notes = format!("# Changes since {}\n\n{}\n", tag, introduction.trim());
+    for (rank, entries) in groups {
+        let heading = match COMMIT_TYPES.get(rank) {
+            Some(&"feat") => "Features",
+            Some(&"fix") => "Bug Fixes",
+            Some(&"docs") => "Documentation",
+            Some(&"style") => "Style",
+            Some(&"refactor") => "Refactoring",
+            Some(&"test") => "Tests",
+            Some(&"chore") => "Chores",
+            _ => "Other Changes",
+        };
+        notes.push_str(&format!("\n## {}\n\n", heading));
+        for entry in entries {
+            notes.push_str(&format!("- {}\n", entry));
+        }
+    }
+    Ok(notes)
+}
+
+pub struct PrSummary {
+    pub title: String,
+    pub body: String,
+}
+
+pub async fn generate_pr_summary(
+    session: &mut Session,
+    config: &Config,
+    changes: &GitChanges,
+    subjects: &[String],
+    verbosity: Verbosity,
+) -> Result<PrSummary> {
+    let DescribedChanges { changes_text, changed_symbols, .. } =
+        describe_changes(session, config, changes, verbosity).await?;
+
+    let commit_subjects = subjects.iter().map(|subject| format!("- {}\n", subject)).collect::<String>();
+    let response = ChangePrompt {
+        stage: "pr_summary",
+        doing: "Generating PR summary",
+        system: &config.prompts.pr_system,
+        template: &config.prompts.pr_context,
+        changes,
+        changes_text: &changes_text,
+        changed_symbols: &changed_symbols,
+        extra: vec![(config.prompts.placeholders.commit_subjects.as_str(), commit_subjects.as_str())],
+        stop: Some("</pr>".to_string()),
+    }
+    .send(session, config, verbosity)
+    .await
+    .context("Failed to generate PR summary")?;
+    if verbosity.xml() {
+        println!("=== Debug: Raw LLM Response ===\n{}\n===\n", response);
+    }
+
+    let title = tag_content(&response, "title")
+        .ok_or_else(|| anyhow::anyhow!("Model response has no <title>:\n{}", response))?;
+    // the stop sequence can cut off </body> along with </pr>
+    let body = tag_content(&response, "body")
+        .or_else(|| response.find("<body>").map(|start| response[start + 6..].trim()))
+        .unwrap_or("");
+    Ok(PrSummary {
+        title: title.to_string(),
+        body: body.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit_config(change: impl FnOnce(&mut CommitConfig)) -> CommitConfig {
+        let config: Config = toml::from_str(include_str!("../config/default.toml")).unwrap();
+        let mut commit = config.commit;
+        commit.conventional = false;
+        commit.emoji = false;
+        change(&mut commit);
+        commit
+    }
+
+    fn styled(message: &str, commit: &CommitConfig) -> String {
+        style_subject(message.to_string(), None, commit, Verbosity::default())
+    }
+
+    fn changes(files: &[(&str, usize)]) -> GitChanges {
+        let files = files.iter().map(|(path, lines)| {
+            let change = FileChange {
+                status: "modified".to_string(),
+                diff: "+line\n".repeat(*lines),
+                line_count: *lines,
+                ..FileChange::default()
+            };
+            (path.to_string(), change)
+        }).collect();
+        GitChanges { staged: Vec::new(), unstaged: Vec::new(), committed: Vec::new(), files, summary: String::new() }
+    }
+
+    fn selection(response: &str, strict_xml: bool) -> (BTreeSet<String>, BTreeSet<String>) {
+        let mut config: Config = toml::from_str(include_str!("../config/default.toml")).unwrap();
+        config.model.strict_xml = strict_xml;
+        let changes = changes(&[("src/main.rs", 40), ("src/lib.rs", 20), ("docs/guide.md", 30), ("build.sh", 10)]);
+
+        let (response_text, untagged) = normalize_selection_response(response, strict_xml, &config.prompts.tags);
+        let mut files = parse_selected_files(&response_text, &config.prompts.tags, &changes);
+        let from_model = files.clone();
+        let wanted = if untagged { config.selection.max_files } else { config.selection.min_files };
+        fill_selection(&mut files, wanted, &config, &changes);
+        (from_model, files)
+    }
+
+    #[test]
+    fn selection_takes_tagged_files_and_drops_made_up_ones() {
+        let (from_model, files) = selection("<files>\n  <file>src/lib.rs</file>\n  <file>src/missing.rs</file>\n  <file>build.sh</file>", true);
+        assert_eq!(from_model, BTreeSet::from(["build.sh".to_string(), "src/lib.rs".to_string()]));
+        assert_eq!(files, from_model);
+    }
+
+    #[test]
+    fn selection_tops_up_a_short_answer_by_heuristics() {
+        // no opening tag and one usable file; min_files is 2
+        let (from_model, files) = selection("Sure! Here you go:\n<file>build.sh</file>", false);
+        assert_eq!(from_model, BTreeSet::from(["build.sh".to_string()]));
+        // rs is a priority extension and main.rs has the biggest diff
+        assert_eq!(files, BTreeSet::from(["build.sh".to_string(), "src/main.rs".to_string()]));
+    }
+
+    #[test]
+    fn strict_selection_without_the_tag_ignores_prose_and_fills_to_max_files() {
+        let (from_model, files) = selection("I would look at <file>build.sh</file> first.", true);
+        assert!(from_model.is_empty());
+        assert_eq!(files.len(), 4);
+    }
+
+    #[test]
+    fn subject_case_any_leaves_the_subject_alone() {
+        let commit = commit_config(|commit| commit.subject_case = SubjectCase::Any);
+        assert_eq!(styled("Add parser", &commit), "Add parser");
+        assert_eq!(styled("add parser", &commit), "add parser");
+    }
+
+    #[test]
+    fn subject_case_lower_lowercases_the_first_letter() {
+        let commit = commit_config(|commit| commit.subject_case = SubjectCase::Lower);
+        assert_eq!(styled("Add parser", &commit), "add parser");
+        assert_eq!(styled("fix: Handle empty input", &commit), "fix: handle empty input");
+        // acronyms and identifiers keep their case
+        assert_eq!(styled("README tweaks", &commit), "README tweaks");
+    }
+
+    #[test]
+    fn subject_case_sentence_capitalizes_after_the_type() {
+        let commit = commit_config(|commit| commit.subject_case = SubjectCase::Sentence);
+        assert_eq!(styled("add parser", &commit), "Add parser");
+        assert_eq!(styled("feat(cli): add --pager", &commit), "feat(cli): Add --pager");
+    }
+
+    #[test]
+    fn emoji_without_conventional_infers_a_type_for_the_emoji_only() {
+        let commit = commit_config(|commit| commit.emoji = true);
+        assert_eq!(styled("Fix crash on empty input", &commit), "🐛 Fix crash on empty input");
+        assert_eq!(styled("Add export command", &commit), "✨ Add export command");
+        assert_eq!(styled("Update dependencies", &commit), "🔨 Update dependencies");
+    }
+
+    #[test]
+    fn emoji_without_conventional_uses_the_suggested_type() {
+        let commit = commit_config(|commit| commit.emoji = true);
+        let message = style_subject("Describe the config keys".to_string(), Some("docs"), &commit, Verbosity::default());
+        assert_eq!(message, "📚 Describe the config keys");
+    }
+
+    #[test]
+    fn emoji_after_type_without_a_type_goes_in_front() {
+        let commit = commit_config(|commit| {
+            commit.emoji = true;
+            commit.emoji_position = EmojiPosition::AfterType;
+        });
+        assert_eq!(styled("Fix crash on empty input", &commit), "🐛 Fix crash on empty input");
+    }
+
+    #[test]
+    fn emoji_with_conventional_follows_the_type_prefix() {
+        let commit = commit_config(|commit| {
+            commit.emoji = true;
+            commit.conventional = true;
+        });
+        assert_eq!(styled("Fix crash on empty input", &commit), "🐛 fix: Fix crash on empty input");
+        assert_eq!(styled("docs: describe the config", &commit), "📚 docs: describe the config");
+    }
+
+    #[test]
+    fn strip_trailing_period_keeps_ellipses() {
+        let commit = commit_config(|commit| commit.strip_trailing_period = true);
+        assert_eq!(styled("Add parser.", &commit), "Add parser");
+        assert_eq!(styled("Add parser...", &commit), "Add parser...");
+    }
+
+    #[test]
+    fn subject_limit_counts_the_ticket_prefix() {
+        let message = prefix_ticket("fix: Handle empty input in parser\n\n- body", "PROJ-123", "{ticket}: ");
+        assert_eq!(message, "fix: PROJ-123: Handle empty input in parser\n\n- body");
+        let limited = limit_subject(&message, 30);
+        assert_eq!(limited, "fix: PROJ-123: Handle empty\n\n- body");
+        assert!(limited.lines().next().unwrap().chars().count() <= 30);
+    }
+
+    #[test]
+    fn subject_is_only_cut_once_with_the_ticket_in_place() {
+        let commit = commit_config(|commit| commit.max_subject_length = Some(30));
+        let message = styled("Handle empty input in the parser", &commit);
+        assert_eq!(message, "Handle empty input in the parser");
+        let limited = limit_subject(&prefix_ticket(&message, "PROJ-123", "{ticket}: "), 30);
+        assert_eq!(limited, "PROJ-123: Handle empty input");
+    }
+
+    #[test]
+    fn change_prompts_fill_the_shared_placeholders_and_the_callers_own() {
+        let config: Config = toml::from_str(include_str!("../config/default.toml")).unwrap();
+        let changes = changes(&[("src/main.rs", 4)]);
+        let prompt = ChangePrompt {
+            stage: "review",
+            doing: "Reviewing commit message",
+            system: "",
+            template: "{subject} | {changes_text} | {changed_symbols} | {max_message_length} |{indent}|",
+            changes: &changes,
+            changes_text: "the diff",
+            changed_symbols: "fn main",
+            extra: vec![("{subject}", "Fix it")],
+            stop: None,
+        };
+        let indent = " ".repeat(config.formatting.indent_size);
+        let expected = format!("Fix it | the diff | fn main | {} |{}|", config.commit.max_message_length, indent);
+        assert_eq!(prompt.context(&config), expected);
+    }
+
+    #[test]
+    fn answers_cut_at_the_stop_sequence_keep_what_follows_the_opening_tag() {
+        assert_eq!(after_open_tag("Sure!\n<message>Fix the parser\n", "message"), "Fix the parser");
+        assert_eq!(after_open_tag("  Fix the parser  ", "message"), "Fix the parser");
+    }
+}
@@ -1,30 +1,306 @@
-use std::path::PathBuf;
-use anyhow::Result;
-use crate::config::Config;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use anyhow::{Context, Result};
+use crate::config::{Config, Rewrite};
+use crate::error::CommitGenError;
 
-pub fn load_config(config_path: Option<PathBuf>) -> Result<Config> {
+pub fn load_config(config_path: Option<PathBuf>, config_dir: Option<PathBuf>) -> Result<Config> {
     if let Some(path) = config_path {
-        let file = std::fs::read_to_string(path)?;
-        return Ok(toml::from_str(&file)?);
+        return read_config(&path);
     }
     // it doesn't make sense to use a macro here
-    let home =
-        dirs::home_dir().ok_or_else(|| anyhow::anyhow!("Could not determine home directory"))?;
+    // --config-dir stands in for the home directory as the search root
+    let (home, xdg_config) = match config_dir {
+        Some(dir) => (dir, None),
+        None => (
+            dirs::home_dir().ok_or_else(|| anyhow::anyhow!("Could not determine home directory"))?,
+            dirs::config_dir(),
+        ),
+    };
 
-    // find the config file with 4 path options
-    let config_paths = [
-        PathBuf::from("config/default.toml"),
+    // find the config file: local, then XDG_CONFIG_HOME (or platform equivalent), then legacy home paths
+    let mut config_paths = vec![PathBuf::from("config/default.toml")];
+    if let Some(xdg_config) = xdg_config {
+        config_paths.push(xdg_config.join("commit-gen/config.toml"));
+    }
+    config_paths.extend([
         home.join(".config/commit-gen/config.toml"),
         home.join(".commit-gen/config.toml"),
         home.join(".commit-gen.toml"),
-    ];
+    ]);
     // prefers .config/ over .commit-gen/ over .commit-gen.toml
     for path in &config_paths {
         if path.exists() {
-            let file = std::fs::read_to_string(path)?;
-            return Ok(toml::from_str(&file)?);
+            return read_config(path);
         }
     }
     // must have a config file to use commit-gen... specify root_dir
-    Err(anyhow::anyhow!("Cannot find a config file"))
-}
\ No newline at end of file
+    Err(CommitGenError::ConfigNotFound.into())
+}
+
+fn read_config(path: &Path) -> Result<Config> {
+    let file = std::fs::read_to_string(path)?;
+    let mut table: toml::Table = toml::from_str(&file)?;
+    if let Some(toml::Value::Table(prompts)) = table.get_mut("prompts") {
+        inline_includes(prompts, path.parent().unwrap_or(Path::new(".")), None)?;
+    }
+    Ok(table.try_into()?)
+}
+
+// a prompt written as `{ include = "path" }` is replaced by that file's contents,
+// the path being relative to the file that names it. a repository's prompts come with
+// whatever was cloned, so they may only include files inside `confine_to`, or they
+// could send any local file to the model
+fn inline_includes(table: &mut toml::Table, base_dir: &Path, confine_to: Option<&Path>) -> Result<()> {
+    for (_, value) in table.iter_mut() {
+        let toml::Value::Table(inner) = value else {
+            continue;
+        };
+        match inner.get("include") {
+            Some(toml::Value::String(include)) if inner.len() == 1 => {
+                let path = base_dir.join(include);
+                if let Some(root) = confine_to {
+                    let resolved = path.canonicalize()
+                        .with_context(|| format!("Failed to read included prompt {}", path.display()))?;
+                    let root = root.canonicalize()
+                        .with_context(|| format!("Failed to resolve {}", root.display()))?;
+                    if !resolved.starts_with(&root) {
+                        anyhow::bail!("Refusing to include {}: it is outside the repository", path.display());
+                    }
+                }
+                let contents = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read included prompt {}", path.display()))?;
+                *value = toml::Value::String(contents);
+            }
+            _ => inline_includes(inner, base_dir, confine_to)?,
+        }
+    }
+    Ok(())
+}
+
+// a repo can ship .commit-gen/prompts.toml; any keys in its [prompts] section
+// replace the loaded ones, the rest of the config is left alone
+pub fn apply_repo_prompts(config: &mut Config, repo_root: &Path) -> Result<()> {
+    let path = repo_root.join(".commit-gen/prompts.toml");
+    if !path.exists() {
+        return Ok(());
+    }
+    let file = std::fs::read_to_string(&path)?;
+    let mut overrides: toml::Table = toml::from_str(&file)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let Some(toml::Value::Table(prompt_overrides)) = overrides.get_mut("prompts") else {
+        return Ok(());
+    };
+    inline_includes(prompt_overrides, &repo_root.join(".commit-gen"), Some(repo_root))?;
+
+    let mut prompts = toml::Table::try_from(&config.prompts)?;
+    merge_tables(&mut prompts, prompt_overrides);
+    config.prompts = prompts.try_into()
+        .with_context(|| format!("Invalid [prompts] in {}", path.display()))?;
+    Ok(())
+}
+
+fn merge_tables(base: &mut toml::Table, overrides: &toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overrides)) => merge_tables(base, overrides),
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+// git reads trailers from the last paragraph only, so join one that is already there
+// rather than starting a second block
+pub fn append_trailers(message: &str, trailers: &[String]) -> String {
+    if trailers.is_empty() {
+        return message.to_string();
+    }
+    let message = message.trim_end();
+    let trailer_re = regex::Regex::new(r"^[A-Za-z0-9-]+: ").unwrap();
+    let last_paragraph = message.rsplit("\n\n").next().unwrap_or("");
+    let has_block = message.contains("\n\n") && last_paragraph.lines().all(|line| trailer_re.is_match(line));
+    let separator = if has_block { "\n" } else { "\n\n" };
+    format!("{}{}{}", message, separator, trailers.join("\n"))
+}
+
+// 1.0 for texts that match ignoring case, falling toward 0.0 with the edit distance
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.to_lowercase().chars().collect();
+    let b: Vec<char> = b.to_lowercase().chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == cb { diagonal } else { 1 + diagonal.min(above).min(row[j]) };
+            diagonal = above;
+        }
+    }
+    1.0 - row[b.len()] as f64 / longest as f64
+}
+
+fn shell(command: &str) -> Command {
+    let mut shell = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    shell.arg(command);
+    shell
+}
+
+// in order, each on the result of the one before
+pub fn apply_rewrites(message: &str, rewrites: &[Rewrite]) -> Result<String> {
+    let mut message = message.to_string();
+    for rewrite in rewrites {
+        let re = regex::Regex::new(&rewrite.pattern)
+            .with_context(|| format!("Invalid rewrites pattern '{}'", rewrite.pattern))?;
+        message = re.replace_all(&message, rewrite.replacement.as_str()).into_owned();
+    }
+    Ok(message)
+}
+
+pub fn run_post_commit_command(command: &str, sha: &str, subject: &str) -> Result<()> {
+    let status = shell(command)
+        .env("COMMIT_GEN_SHA", sha)
+        .env("COMMIT_GEN_SUBJECT", subject)
+        .status()?;
+
+    if !status.success() {
+        return Err(anyhow::anyhow!("`{}` exited with {}", command, status));
+    }
+    Ok(())
+}
+
+// a COMMIT_EDITMSG as git prepared it: the text the user already wrote (e.g. with -m),
+// and the comment lines plus anything below the scissors line, which git strips itself
+pub struct EditMsg {
+    pub text: String,
+    pub tail: String,
+}
+
+pub fn read_editmsg(path: &Path, comment_char: &str) -> Result<EditMsg> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let scissors = format!("{} ------------------------ >8 ------------------------", comment_char);
+
+    let (mut text, mut tail) = (Vec::new(), Vec::new());
+    let mut lines = content.lines();
+    for line in lines.by_ref() {
+        if line == scissors {
+            tail.push(line);
+            break;
+        }
+        if line.starts_with(comment_char) {
+            tail.push(line);
+        } else {
+            text.push(line);
+        }
+    }
+    tail.extend(lines);
+
+    Ok(EditMsg {
+        text: text.join("\n").trim().to_string(),
+        tail: tail.join("\n"),
+    })
+}
+
+// like git, an empty pager or "cat" means printing straight to the terminal
+pub fn show_in_pager(pager: &str, text: &str) -> Result<()> {
+    if pager.is_empty() || pager == "cat" {
+        println!("{}", text);
+        return Ok(());
+    }
+    let mut command = shell(pager);
+    // git's defaults: quit if it fits on one screen, keep colors, don't clear the screen
+    if std::env::var_os("LESS").is_none() {
+        command.env("LESS", "FRX");
+    }
+    let mut child = command
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to start pager `{}`", pager))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // the user quitting the pager early closes the pipe, which is fine
+        let _ = stdin.write_all(text.as_bytes());
+    }
+    child.wait()
+        .with_context(|| format!("Failed to wait for pager `{}`", pager))?;
+    Ok(())
+}
+
+// run the way git runs its editor, so a configured command with arguments works
+pub fn edit_file(editor: &str, path: &Path) -> Result<()> {
+    let mut command = if cfg!(windows) {
+        shell(&format!("{} \"{}\"", editor, path.display()))
+    } else {
+        let mut command = shell(&format!("{} \"$@\"", editor));
+        command.arg(editor).arg(path);
+        command
+    };
+    let status = command.status()
+        .with_context(|| format!("Failed to start editor `{}`", editor))?;
+    if !status.success() {
+        anyhow::bail!("Editor `{}` exited with {}", editor, status);
+    }
+    Ok(())
+}
+
+// one JSON record per line so the log can be appended to and grepped
+pub fn append_log(path: &Path, record: &serde_json::Value) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", record)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn include(path: &Path) -> toml::Table {
+        toml::from_str(&format!("commit_system = {{ include = {:?} }}", path.display().to_string())).unwrap()
+    }
+
+    #[test]
+    fn repo_prompts_can_include_files_inside_the_repo() {
+        let repo = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(repo.path().join(".commit-gen/prompts")).unwrap();
+        std::fs::write(repo.path().join(".commit-gen/prompts/system.md"), "be brief").unwrap();
+
+        let mut table = include(Path::new("prompts/system.md"));
+        inline_includes(&mut table, &repo.path().join(".commit-gen"), Some(repo.path())).unwrap();
+        assert_eq!(table["commit_system"].as_str(), Some("be brief"));
+    }
+
+    #[test]
+    fn repo_prompts_cannot_include_files_outside_the_repo() {
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), "key").unwrap();
+        let repo = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(repo.path().join(".commit-gen")).unwrap();
+        let base = repo.path().join(".commit-gen");
+
+        let relative = Path::new("../../..").join(outside.path().strip_prefix("/").unwrap()).join("secret");
+        for path in [outside.path().join("secret"), relative] {
+            let mut table = include(&path);
+            let error = inline_includes(&mut table, &base, Some(repo.path())).unwrap_err();
+            assert!(error.to_string().contains("outside the repository"), "{:#}", error);
+        }
+    }
+}
@@ -0,0 +1,30 @@
+mod common;
+
+use common::{TestRepo, INITIAL_TIME};
+
+#[test]
+fn plain_amend_keeps_the_author_and_committer_dates() {
+    let repo = TestRepo::new(&[("a.txt", "one\n")]);
+    repo.write("a.txt", "two\n");
+
+    repo.commit_gen(".", &["--amend", "-m", "Amended", "-y"]);
+
+    let head = repo.head();
+    assert_eq!(head.summary(), Some("Amended"));
+    assert_eq!(head.parent_count(), 0);
+    assert_eq!(repo.committed("a.txt").as_deref(), Some("two\n"));
+    assert_eq!(head.author().when().seconds(), INITIAL_TIME);
+    assert_eq!(head.committer().when().seconds(), INITIAL_TIME);
+}
+
+#[test]
+fn amend_with_a_date_moves_only_what_it_is_given() {
+    let repo = TestRepo::new(&[("a.txt", "one\n")]);
+    repo.write("a.txt", "two\n");
+
+    repo.commit_gen(".", &["--amend", "--author-date", "2024-03-20 15:30:00", "-m", "Amended", "-y"]);
+
+    let head = repo.head();
+    assert_ne!(head.author().when().seconds(), INITIAL_TIME);
+    assert_eq!(head.committer().when().seconds(), INITIAL_TIME);
+}
@@ -0,0 +1,32 @@
+mod common;
+
+use common::TestRepo;
+use std::time::{Duration, Instant};
+
+#[test]
+fn background_with_yes_writes_the_message() {
+    let repo = TestRepo::new(&[("a.txt", "one\n")]);
+    repo.write("a.txt", "two\n");
+
+    let output = repo.commit_gen(".", &["--background", "-y", "-m", "In the background"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let id = stdout.lines()
+        .find_map(|line| line.strip_prefix("Started background job "))
+        .unwrap_or_else(|| panic!("no job id in:\n{}", stdout))
+        .trim()
+        .to_string();
+
+    let started = Instant::now();
+    loop {
+        let output = repo.commit_gen(".", &["--result", &id]);
+        let status = String::from_utf8_lossy(&output.stdout).into_owned();
+        if !status.contains("still running") {
+            assert_eq!(status.trim(), "In the background");
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(30), "job {} never finished", id);
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    // a job only writes the message, it doesn't commit
+    assert_eq!(repo.head().summary(), Some("Initial commit"));
+}
@@ -0,0 +1,132 @@
+// a scratch repository to run the built binary in; -m and the template fallback keep
+// these runs from needing an Ollama server
+#![allow(dead_code)]
+
+use git2::{Repository, Signature, Time};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+pub struct TestRepo {
+    pub dir: TempDir,
+    pub repo: Repository,
+    config: PathBuf,
+}
+
+// a fixed date for the commits made here, to tell them from ones dated now
+pub const INITIAL_TIME: i64 = 1_600_000_000;
+
+impl TestRepo {
+    // one commit holding `files`
+    pub fn new(files: &[(&str, &str)]) -> TestRepo {
+        Self::with_config(files, |config| config)
+    }
+
+    // `edit` gets config/default.toml with the model pointed at a closed port, and
+    // generation failures falling back to the template message
+    pub fn with_config(files: &[(&str, &str)], edit: impl FnOnce(String) -> String) -> TestRepo {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        {
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "Test").unwrap();
+            config.set_str("user.email", "test@example.com").unwrap();
+        }
+
+        let config = include_str!("../../config/default.toml")
+            .replace("host = \"http://localhost\"", "host = \"http://127.0.0.1\"")
+            .replace("port = 11434", "port = 9")
+            .replace("fallback_on_error = false", "fallback_on_error = true");
+        let config_path = dir.path().join(".git/commit-gen.toml");
+        std::fs::write(&config_path, edit(config)).unwrap();
+
+        let test_repo = TestRepo { dir, repo, config: config_path };
+        for (path, contents) in files {
+            test_repo.write(path, contents);
+        }
+        test_repo.commit_all("Initial commit");
+        test_repo
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn config(&self) -> &Path {
+        &self.config
+    }
+
+    pub fn write(&self, path: &str, contents: &str) {
+        let path = self.path().join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    pub fn stage(&self, path: &str) {
+        let mut index = self.repo.index().unwrap();
+        index.add_path(Path::new(path)).unwrap();
+        index.write().unwrap();
+    }
+
+    fn commit_all(&self, message: &str) {
+        let mut index = self.repo.index().unwrap();
+        index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None).unwrap();
+        index.write().unwrap();
+        let tree = self.repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::new("Test", "test@example.com", &Time::new(INITIAL_TIME, 0)).unwrap();
+        self.repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &[]).unwrap();
+    }
+
+    // runs commit-gen in `cwd` (relative to the repository) with this repository's config
+    pub fn commit_gen(&self, cwd: &str, args: &[&str]) -> Output {
+        commit_gen(&self.path().join(cwd), &self.config, args)
+    }
+
+    // like commit_gen, for runs that are meant to fail
+    pub fn run(&self, cwd: &str, args: &[&str]) -> Output {
+        run(&self.path().join(cwd), &self.config, args)
+    }
+
+    pub fn head(&self) -> git2::Commit<'_> {
+        self.repo.head().unwrap().peel_to_commit().unwrap()
+    }
+
+    // the file's contents in HEAD, None if it isn't there
+    pub fn committed(&self, path: &str) -> Option<String> {
+        file_in(&self.repo, &self.head(), path)
+    }
+}
+
+pub fn file_in(repo: &Repository, commit: &git2::Commit, path: &str) -> Option<String> {
+    let entry = commit.tree().unwrap().get_path(Path::new(path)).ok()?;
+    let blob = entry.to_object(repo).unwrap().peel_to_blob().unwrap();
+    Some(String::from_utf8_lossy(blob.content()).into_owned())
+}
+
+pub fn commit_gen(cwd: &Path, config: &Path, args: &[&str]) -> Output {
+    let output = run(cwd, config, args);
+    assert!(
+        output.status.success(),
+        "commit-gen {:?} failed:\n{}\n{}",
+        args,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+pub fn run(cwd: &Path, config: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_commit-gen"))
+        .current_dir(cwd)
+        .arg("--config")
+        .arg(config)
+        .args(args)
+        .env("NO_COLOR", "1")
+        .env_remove("GIT_DIR")
+        .env_remove("GIT_WORK_TREE")
+        .env_remove("GIT_INDEX_FILE")
+        .env_remove("GIT_AUTHOR_DATE")
+        .env_remove("GIT_COMMITTER_DATE")
+        .output()
+        .unwrap()
+}
@@ -0,0 +1,17 @@
+mod common;
+
+use common::TestRepo;
+
+#[test]
+fn excluded_files_are_committed_with_a_warning() {
+    let repo = TestRepo::new(&[("a.txt", "one\n"), ("Cargo.lock", "one\n")]);
+    repo.write("a.txt", "two\n");
+    repo.write("Cargo.lock", "two\n");
+
+    let output = repo.commit_gen(".", &["-m", "Bump", "-y"]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let warning = stdout.split("git.exclude_patterns):").nth(1).unwrap_or_else(|| panic!("no warning in:\n{}", stdout));
+    assert!(warning.lines().nth(1).unwrap().contains("Cargo.lock"), "{}", stdout);
+    assert_eq!(repo.committed("Cargo.lock").as_deref(), Some("two\n"));
+}
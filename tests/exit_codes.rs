@@ -0,0 +1,20 @@
+mod common;
+
+use common::TestRepo;
+
+#[test]
+fn running_out_of_model_calls_is_a_model_failure() {
+    let repo = TestRepo::with_config(&[("a.txt", "one\n")], |config| {
+        config
+            .replace("fallback_on_error = true", "fallback_on_error = false")
+            .replace("# max_model_calls = 10", "max_model_calls = 0")
+    });
+    repo.write("a.txt", "two\n");
+
+    let output = repo.run(".", &["-y"]);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("max_model_calls"), "{}", stderr);
+    assert_eq!(output.status.code(), Some(5), "{}", stderr);
+    assert_eq!(repo.head().summary(), Some("Initial commit"));
+}
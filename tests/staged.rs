@@ -0,0 +1,40 @@
+mod common;
+
+use common::TestRepo;
+
+#[test]
+fn without_include_unstaged_a_file_changed_after_staging_is_committed_as_staged() {
+    let repo = TestRepo::with_config(&[("a.txt", "one\n")], |config| {
+        config.replace("include_unstaged = true", "include_unstaged = false")
+    });
+    repo.write("a.txt", "two\n");
+    repo.stage("a.txt");
+    repo.write("a.txt", "three\n");
+
+    repo.commit_gen(".", &["-m", "Staged only", "-y"]);
+
+    assert_eq!(repo.committed("a.txt").as_deref(), Some("two\n"));
+    let on_disk = std::fs::read_to_string(repo.path().join("a.txt")).unwrap();
+    assert_eq!(on_disk, "three\n");
+}
+
+#[test]
+fn staged_describes_and_commits_only_the_index() {
+    let repo = TestRepo::new(&[("a.txt", "one\n"), ("b.txt", "one\n")]);
+    repo.write("a.txt", "two\n");
+    repo.stage("a.txt");
+    repo.write("b.txt", "two\n");
+    repo.write("c.txt", "new\n");
+
+    // the server is unreachable, so this is the template message built from the changes
+    repo.commit_gen(".", &["--staged", "-y"]);
+
+    let head = repo.head();
+    let message = head.message().unwrap();
+    assert!(message.contains("a.txt"), "{}", message);
+    assert!(!message.contains("b.txt"), "{}", message);
+    assert!(!message.contains("c.txt"), "{}", message);
+    assert_eq!(repo.committed("a.txt").as_deref(), Some("two\n"));
+    assert_eq!(repo.committed("b.txt").as_deref(), Some("one\n"));
+    assert_eq!(repo.committed("c.txt"), None);
+}
@@ -0,0 +1,28 @@
+mod common;
+
+use common::TestRepo;
+
+#[test]
+fn models_asks_the_configured_server() {
+    let repo = TestRepo::new(&[("a.txt", "one\n")]);
+
+    let output = repo.run(".", &["models"]);
+
+    // nothing listens on the test config's port
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("not reachable at http://127.0.0.1:9"), "{}", stderr);
+    assert_eq!(output.status.code(), Some(5));
+}
+
+#[test]
+fn review_reads_the_commit_then_asks_the_server() {
+    let repo = TestRepo::new(&[("a.txt", "one\n")]);
+
+    let missing = repo.run(".", &["review", "no-such-rev"]);
+    assert_eq!(missing.status.code(), Some(1));
+
+    let output = repo.run(".", &["review", "HEAD"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("not reachable at http://127.0.0.1:9"), "{}", stderr);
+    assert_eq!(output.status.code(), Some(5));
+}
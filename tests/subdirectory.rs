@@ -0,0 +1,24 @@
+mod common;
+
+use common::TestRepo;
+
+#[test]
+fn running_from_a_nested_directory_commits_the_whole_repository() {
+    let repo = TestRepo::new(&[("root.txt", "one\n"), ("gone.txt", "bye\n"), ("nested/n.txt", "one\n")]);
+    repo.write("root.txt", "two\n");
+    repo.write("nested/n.txt", "two\n");
+    repo.write("nested/deep/new.txt", "new\n");
+    std::fs::remove_file(repo.path().join("gone.txt")).unwrap();
+
+    // the server is unreachable, so this is the template message built from the changes
+    repo.commit_gen("nested/deep", &["-y"]);
+
+    let message = repo.head().message().unwrap().to_string();
+    for path in ["root.txt", "nested/n.txt", "nested/deep/new.txt", "gone.txt"] {
+        assert!(message.contains(path), "{} missing from:\n{}", path, message);
+    }
+    assert_eq!(repo.committed("root.txt").as_deref(), Some("two\n"));
+    assert_eq!(repo.committed("nested/n.txt").as_deref(), Some("two\n"));
+    assert_eq!(repo.committed("nested/deep/new.txt").as_deref(), Some("new\n"));
+    assert_eq!(repo.committed("gone.txt"), None);
+}
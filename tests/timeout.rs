@@ -0,0 +1,40 @@
+mod common;
+
+use common::TestRepo;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+#[test]
+fn timeout_fires_while_waiting_at_a_prompt() {
+    let repo = TestRepo::new(&[("a.txt", "one\n")]);
+    repo.write("a.txt", "two\n");
+
+    // stdin stays open and silent, so the confirmation prompt blocks in read_line
+    let mut child = Command::new(env!("CARGO_BIN_EXE_commit-gen"))
+        .current_dir(repo.path())
+        .arg("--config")
+        .arg(repo.config())
+        .args(["--timeout", "1", "-m", "Never confirmed"])
+        .env("NO_COLOR", "1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let _stdin = child.stdin.take();
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if started.elapsed() > Duration::from_secs(30) {
+            child.kill().unwrap();
+            panic!("--timeout 1 didn't stop a run blocked at a prompt");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+
+    assert_eq!(status.code(), Some(6));
+    assert_eq!(repo.head().summary(), Some("Initial commit"));
+}
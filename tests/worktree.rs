@@ -0,0 +1,30 @@
+mod common;
+
+use common::{commit_gen, file_in, TestRepo};
+
+#[test]
+fn commits_in_a_linked_worktree_go_to_its_branch() {
+    let repo = TestRepo::new(&[("a.txt", "one\n")]);
+    let main_head = repo.head().id();
+
+    let outside = tempfile::tempdir().unwrap();
+    let worktree_path = outside.path().join("feature");
+    repo.repo.worktree("feature", &worktree_path, None).unwrap();
+    std::fs::write(worktree_path.join("a.txt"), "two\n").unwrap();
+    std::fs::write(worktree_path.join("b.txt"), "new\n").unwrap();
+
+    commit_gen(&worktree_path, repo.config(), &["-m", "Work in the worktree", "-y"]);
+
+    let worktree = git2::Repository::open(&worktree_path).unwrap();
+    let head = worktree.head().unwrap();
+    assert_eq!(head.shorthand(), Some("feature"));
+    let commit = head.peel_to_commit().unwrap();
+    assert_eq!(commit.summary(), Some("Work in the worktree"));
+    assert_eq!(commit.parent_id(0).unwrap(), main_head);
+    assert_eq!(file_in(&worktree, &commit, "a.txt").as_deref(), Some("two\n"));
+    assert_eq!(file_in(&worktree, &commit, "b.txt").as_deref(), Some("new\n"));
+
+    // the main checkout is left alone
+    assert_eq!(repo.head().id(), main_head);
+    assert_eq!(std::fs::read_to_string(repo.path().join("a.txt")).unwrap(), "one\n");
+}